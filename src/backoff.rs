@@ -0,0 +1,30 @@
+// mqtt2db -- subscries to MQTT topics and writes to a database
+// Copyright (C) 2021-2022 Brian Tarricone <brian@tarricone.org>
+// 
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+// 
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+// 
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use rand::Rng;
+use std::time::Duration;
+
+/// Exponential backoff with "equal jitter": half of the delay is fixed, and
+/// the other half is random, so clients that failed together don't all retry
+/// at the same moment.
+pub fn backoff_delay(initial_delay: Duration, max_delay: Duration, attempt: u32) -> Duration {
+    let delay = initial_delay
+        .checked_mul(1 << (attempt - 1).min(31))
+        .unwrap_or(max_delay)
+        .min(max_delay);
+    let half = delay / 2;
+    half + rand::thread_rng().gen_range(Duration::ZERO..=half)
+}
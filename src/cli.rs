@@ -0,0 +1,117 @@
+// mqtt2db -- subscries to MQTT topics and writes to a database
+// Copyright (C) 2021-2022 Brian Tarricone <brian@tarricone.org>
+// 
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+// 
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+// 
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use clap::{Parser, Subcommand};
+use log::LevelFilter;
+use std::path::PathBuf;
+
+pub const DEFAULT_CONFIG_PATH: &str = "/etc/mqtt2db/config.yaml";
+
+#[derive(Debug, Parser)]
+#[command(version, about)]
+pub struct Cli {
+    /// Path to the config file
+    #[arg(short, long, global = true, env = "MQTT2DB_CONFIG", default_value = DEFAULT_CONFIG_PATH)]
+    pub config: PathBuf,
+
+    /// Log level, overriding the one in the config file
+    #[arg(short, long, global = true, value_name = "LEVEL")]
+    pub log_level: Option<LevelFilter>,
+
+    /// Same as the 'check' command (deprecated)
+    #[arg(long, hide = true)]
+    check: bool,
+
+    /// Config file path (deprecated; use --config)
+    #[arg(hide = true)]
+    config_path: Option<PathBuf>,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Subcommand)]
+pub enum Command {
+    /// Connect to the broker and databases and start forwarding messages (default)
+    Run,
+    /// Validate the config file without connecting to anything
+    Check,
+    /// Print the parsed config, with secrets redacted
+    PrintConfig,
+    /// Print the version and exit
+    Version,
+}
+
+impl Cli {
+    pub fn parse_args() -> Cli {
+        Cli::parse().normalize()
+    }
+
+    fn normalize(mut self) -> Cli {
+        // Older versions took the config file as the only positional argument.
+        if let Some(config_path) = self.config_path.take() {
+            self.config = config_path;
+        }
+        self
+    }
+
+    pub fn command(&self) -> Command {
+        match self.command {
+            Some(command) => command,
+            None if self.check => Command::Check,
+            None => Command::Run,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn parse(args: &[&str]) -> anyhow::Result<Cli> {
+        Ok(Cli::try_parse_from([&["mqtt2db"], args].concat())?.normalize())
+    }
+
+    #[test]
+    fn cli_parsing() -> anyhow::Result<()> {
+        let cli = parse(&[])?;
+        assert_eq!(cli.command(), Command::Run);
+        assert_eq!(cli.config, PathBuf::from(DEFAULT_CONFIG_PATH));
+        assert_eq!(cli.log_level, None);
+
+        let cli = parse(&["check", "--config", "foo.yaml", "-l", "debug"])?;
+        assert_eq!(cli.command(), Command::Check);
+        assert_eq!(cli.config, PathBuf::from("foo.yaml"));
+        assert_eq!(cli.log_level, Some(LevelFilter::Debug));
+
+        let cli = parse(&["-c", "foo.yaml", "print-config"])?;
+        assert_eq!(cli.command(), Command::PrintConfig);
+        assert_eq!(cli.config, PathBuf::from("foo.yaml"));
+
+        let cli = parse(&["foo.yaml"])?;
+        assert_eq!(cli.command(), Command::Run);
+        assert_eq!(cli.config, PathBuf::from("foo.yaml"));
+
+        let cli = parse(&["--check", "foo.yaml"])?;
+        assert_eq!(cli.command(), Command::Check);
+        assert_eq!(cli.config, PathBuf::from("foo.yaml"));
+
+        assert!(parse(&["frobnicate", "--now"]).is_err());
+        assert!(parse(&["-l", "loud"]).is_err());
+
+        Ok(())
+    }
+}
@@ -14,55 +14,402 @@
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
+use http::{header::HeaderName, HeaderMap, HeaderValue};
 use log::LevelFilter;
-use serde::Deserialize;
-use serde_yaml::from_str;
+use serde::{Deserialize, Serialize, Serializer};
+use serde_yaml::{from_str, from_value, Mapping as YamlMapping, Value as YamlValue};
 use std::io::Read;
-use std::{collections::HashMap, fs::File, path::Path, time::Duration};
+use std::{
+    collections::{HashMap, HashSet},
+    convert::TryFrom,
+    env,
+    fs::{self, File},
+    path::Path,
+    time::Duration,
+};
+use tokio_postgres::config::{Config as PgConfig, SslMode};
 
 use crate::value::ValueType;
 
-#[derive(Debug, Deserialize)]
-#[serde(rename_all = "camelCase", untagged)]
-pub enum MqttAuth {
-    #[serde(rename_all = "camelCase")]
-    UserPass { username: String, password: String },
-    #[serde(rename_all = "camelCase")]
-    Certificate {
-        cert_file: String,
-        private_key_file: String,
-    },
+/// Keeps secrets out of the config when it's printed.
+fn redact<S: Serializer>(_: &str, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str("<redacted>")
+}
+
+fn redact_option<S: Serializer>(value: &Option<String>, serializer: S) -> Result<S::Ok, S::Error> {
+    match value {
+        Some(_) => serializer.serialize_str("<redacted>"),
+        None => serializer.serialize_none(),
+    }
+}
+
+fn redact_values<S: Serializer>(map: &HashMap<String, String>, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_map(map.keys().map(|key| (key, "<redacted>")))
+}
+
+/// Username/password and client certificate authentication can be used
+/// on their own or together; a client certificate requires a CA file.
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MqttAuth {
+    pub username: Option<String>,
+    #[serde(default, serialize_with = "redact_option")]
+    pub password: Option<String>,
+    pub cert_file: Option<String>,
+    pub private_key_file: Option<String>,
+}
+
+impl MqttAuth {
+    pub fn credentials(&self) -> Option<(&str, &str)> {
+        match (&self.username, &self.password) {
+            (Some(username), password) => Some((username, password.as_deref().unwrap_or(""))),
+            _ => None,
+        }
+    }
+
+    pub fn client_certificate(&self) -> Option<(&str, &str)> {
+        match (&self.cert_file, &self.private_key_file) {
+            (Some(cert_file), Some(private_key_file)) => Some((cert_file, private_key_file)),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(try_from = "RawProtocolVersion", into = "String")]
+pub enum ProtocolVersion {
+    #[default]
+    V311,
+    V5,
+}
+
+/// Lets the protocol version be given either as a number or a string,
+/// since `5` and `3.1.1` parse as different YAML types.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RawProtocolVersion {
+    Number(u64),
+    Text(String),
+}
+
+impl TryFrom<RawProtocolVersion> for ProtocolVersion {
+    type Error = String;
+    fn try_from(raw: RawProtocolVersion) -> Result<Self, Self::Error> {
+        let version = match raw {
+            RawProtocolVersion::Number(number) => number.to_string(),
+            RawProtocolVersion::Text(text) => text,
+        };
+        match version.as_str() {
+            "3.1.1" | "4" => Ok(ProtocolVersion::V311),
+            "5" | "5.0" => Ok(ProtocolVersion::V5),
+            _ => Err(format!("Unsupported MQTT protocol version '{}'; must be '3.1.1' or '5'", version)),
+        }
+    }
+}
+
+impl From<ProtocolVersion> for String {
+    fn from(version: ProtocolVersion) -> Self {
+        match version {
+            ProtocolVersion::V311 => "3.1.1".to_string(),
+            ProtocolVersion::V5 => "5".to_string(),
+        }
+    }
+}
+
+fn default_websocket_path() -> String {
+    "/mqtt".to_string()
+}
+
+/// Connects over WebSockets instead of plain TCP; as with TCP, TLS (wss)
+/// is used when a CA file is given.
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct WebsocketConfig {
+    #[serde(default = "default_websocket_path")]
+    pub path: String,
+    #[serde(default, serialize_with = "redact_values")]
+    pub headers: HashMap<String, String>,
+}
+
+impl WebsocketConfig {
+    pub fn header_map(&self) -> anyhow::Result<HeaderMap> {
+        let mut headers = HeaderMap::new();
+        for (name, value) in self.headers.iter() {
+            let name = HeaderName::try_from(name.as_str())
+                .map_err(|err| anyhow!("Invalid WebSocket header name '{}': {}", name, err))?;
+            let value = HeaderValue::try_from(value.as_str())
+                .map_err(|err| anyhow!("Invalid value for WebSocket header '{}': {}", name, err))?;
+            headers.insert(name, value);
+        }
+        Ok(headers)
+    }
+}
+
+fn default_status_online() -> String {
+    "online".to_string()
 }
 
-#[derive(Debug, Deserialize)]
+fn default_status_offline() -> String {
+    "offline".to_string()
+}
+
+/// A retained topic saying whether mqtt2db is connected, set to `online`
+/// once it's subscribed, and to `offline` by the broker (as our Last Will)
+/// when the connection drops.
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct StatusConfig {
+    pub topic: String,
+    #[serde(default = "default_status_online")]
+    pub online: String,
+    #[serde(default = "default_status_offline")]
+    pub offline: String,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct MqttConfig {
+    /// Defaults to the host name.
+    pub name: Option<String>,
     pub host: String,
     pub port: u16,
     pub client_id: String,
+    #[serde(default)]
+    pub protocol_version: ProtocolVersion,
     pub auth: Option<MqttAuth>,
     pub ca_file: Option<String>,
+    pub websocket: Option<WebsocketConfig>,
     pub connect_timeout: Option<Duration>,
     pub keep_alive: Option<Duration>,
+    /// With `false`, the broker keeps our subscriptions and queues messages
+    /// while we're disconnected.
+    #[serde(default = "default_true")]
+    pub clean_session: bool,
+    /// How long the broker keeps the session after we disconnect; MQTT 5
+    /// only.  Persistent sessions default to never expiring.
+    pub session_expiry: Option<Duration>,
+    #[serde(default)]
+    pub reconnect: ReconnectConfig,
+    pub status: Option<StatusConfig>,
+    /// Subscribe as part of this shared subscription group, so the broker
+    /// spreads messages between the instances in it.
+    pub shared_group: Option<String>,
 }
 
-#[derive(Debug, Deserialize)]
+impl MqttConfig {
+    pub fn name(&self) -> &str {
+        self.name.as_deref().unwrap_or(&self.host)
+    }
+
+    /// The filter to subscribe with for a mapping's topic.
+    pub fn subscription_filter(&self, topic: &str) -> String {
+        match &self.shared_group {
+            Some(group) => format!("$share/{}/{}", group, topic),
+            None => topic.to_string(),
+        }
+    }
+
+    fn validate(&self) -> anyhow::Result<()> {
+        if let Some(auth) = &self.auth {
+            if auth.password.is_some() && auth.username.is_none() {
+                Err(anyhow!("MQTT auth has a password but no username"))?;
+            }
+            if auth.cert_file.is_some() != auth.private_key_file.is_some() {
+                Err(anyhow!("MQTT auth needs both a certFile and a privateKeyFile"))?;
+            }
+            if auth.cert_file.is_some() && self.ca_file.is_none() {
+                Err(anyhow!("MQTT client certificates require a caFile"))?;
+            }
+            if auth.credentials().is_none() && auth.client_certificate().is_none() {
+                Err(anyhow!("MQTT auth needs a username or a client certificate"))?;
+            }
+        }
+
+        if self.session_expiry.is_some() && self.protocol_version != ProtocolVersion::V5 {
+            Err(anyhow!("MQTT sessionExpiry requires protocol version 5"))?;
+        }
+        if !self.clean_session && self.client_id.is_empty() {
+            Err(anyhow!("MQTT persistent sessions require a clientId"))?;
+        }
+        if let Some(group) = &self.shared_group {
+            if group.is_empty() || group.contains(['/', '+', '#']) {
+                Err(anyhow!("MQTT sharedGroup '{}' must be non-empty and can't contain '/', '+' or '#'", group))?;
+            }
+        }
+        if let Some(websocket) = &self.websocket {
+            if !websocket.path.starts_with('/') {
+                Err(anyhow!("WebSocket path '{}' must start with '/'", websocket.path))?;
+            }
+            websocket.header_map()?;
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct TagValue {
     pub r#type: ValueType,
     pub value: String,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct UserAuth {
     pub username: String,
+    #[serde(serialize_with = "redact")]
     pub password: String,
 }
 
-#[derive(Debug, Deserialize)]
+fn default_time_column() -> String {
+    "time".to_string()
+}
+
+fn default_field_column() -> String {
+    "field".to_string()
+}
+
+fn default_value_column() -> String {
+    "value".to_string()
+}
+
+fn default_value_type() -> ValueType {
+    ValueType::Float
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SqlTable {
+    pub name: String,
+    #[serde(default = "default_time_column")]
+    pub time_column: String,
+    #[serde(default = "default_field_column")]
+    pub field_column: String,
+    #[serde(default = "default_value_column")]
+    pub value_column: String,
+    #[serde(default = "default_value_type")]
+    pub value_type: ValueType,
+    #[serde(default)]
+    pub create: bool,
+    #[serde(default)]
+    pub hypertable: bool,
+}
+
+fn default_batch_max_size() -> usize {
+    100
+}
+
+fn default_batch_max_age() -> Duration {
+    Duration::from_secs(1)
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BatchConfig {
+    #[serde(default = "default_batch_max_size")]
+    pub max_size: usize,
+    #[serde(default = "default_batch_max_age")]
+    pub max_age: Duration,
+}
+
+impl Default for BatchConfig {
+    fn default() -> Self {
+        BatchConfig {
+            max_size: default_batch_max_size(),
+            max_age: default_batch_max_age(),
+        }
+    }
+}
+
+fn default_retry_max_attempts() -> u32 {
+    5
+}
+
+fn default_retry_initial_delay() -> Duration {
+    Duration::from_millis(500)
+}
+
+fn default_retry_max_delay() -> Duration {
+    Duration::from_secs(30)
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RetryConfig {
+    #[serde(default = "default_retry_max_attempts")]
+    pub max_attempts: u32,
+    #[serde(default = "default_retry_initial_delay")]
+    pub initial_delay: Duration,
+    #[serde(default = "default_retry_max_delay")]
+    pub max_delay: Duration,
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        RetryConfig {
+            max_attempts: default_retry_max_attempts(),
+            initial_delay: default_retry_initial_delay(),
+            max_delay: default_retry_max_delay(),
+        }
+    }
+}
+
+fn default_reconnect_initial_delay() -> Duration {
+    Duration::from_secs(1)
+}
+
+fn default_reconnect_max_delay() -> Duration {
+    Duration::from_secs(60)
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ReconnectConfig {
+    #[serde(default = "default_reconnect_initial_delay")]
+    pub initial_delay: Duration,
+    #[serde(default = "default_reconnect_max_delay")]
+    pub max_delay: Duration,
+}
+
+impl Default for ReconnectConfig {
+    fn default() -> Self {
+        ReconnectConfig {
+            initial_delay: default_reconnect_initial_delay(),
+            max_delay: default_reconnect_max_delay(),
+        }
+    }
+}
+
+fn default_spool_max_size() -> u64 {
+    100 * 1024 * 1024
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum DropPolicy {
+    #[default]
+    Oldest,
+    Newest,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SpoolConfig {
+    pub path: String,
+    #[serde(default = "default_spool_max_size")]
+    pub max_size: u64,
+    #[serde(default)]
+    pub drop_policy: DropPolicy,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+#[derive(Debug, Deserialize, Serialize)]
 #[serde(rename_all = "kebab-case", tag = "type")]
-pub enum Database {
+pub enum DatabaseType {
     #[serde(rename_all = "camelCase")]
     Influxdb {
         url: String,
@@ -70,33 +417,142 @@ pub enum Database {
         db_name: String,
         measurement: String,
     },
+    #[serde(rename_all = "camelCase")]
+    Influxdb2 {
+        url: String,
+        org: String,
+        bucket: String,
+        #[serde(serialize_with = "redact")]
+        token: String,
+        measurement: String,
+    },
+    #[serde(rename_all = "camelCase")]
+    Postgres {
+        #[serde(serialize_with = "redact")]
+        connection: String,
+        table: SqlTable,
+    },
+    #[serde(rename_all = "camelCase")]
+    Sqlite {
+        path: String,
+        table: SqlTable,
+        #[serde(default = "default_true")]
+        wal: bool,
+    },
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Database {
+    pub name: Option<String>,
+    #[serde(flatten)]
+    pub r#type: DatabaseType,
+    #[serde(default)]
+    pub batch: BatchConfig,
+    #[serde(default)]
+    pub retry: RetryConfig,
+    pub spool: Option<SpoolConfig>,
+}
+
+impl Database {
+    /// The table of a SQL database.
+    pub fn sql_table(&self) -> Option<&SqlTable> {
+        match &self.r#type {
+            DatabaseType::Postgres { table, .. } | DatabaseType::Sqlite { table, .. } => Some(table),
+            DatabaseType::Influxdb { .. } | DatabaseType::Influxdb2 { .. } => None,
+        }
+    }
+
+    fn validate(&self) -> anyhow::Result<()> {
+        match &self.r#type {
+            DatabaseType::Postgres { connection, .. } => {
+                let config = connection
+                    .parse::<PgConfig>()
+                    .map_err(|err| anyhow!("Invalid PostgreSQL connection string: {}", err))?;
+                if !matches!(config.get_ssl_mode(), SslMode::Disable | SslMode::Prefer) {
+                    Err(anyhow!("TLS connections to PostgreSQL are not supported; use sslmode=disable or prefer"))?;
+                }
+            }
+            DatabaseType::Sqlite { table, .. } if table.hypertable => {
+                Err(anyhow!("Hypertables are not supported by SQLite"))?;
+            }
+            _ => (),
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct JsonField {
+    pub name: String,
+    pub path: String,
+    pub value_type: ValueType,
 }
 
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Deserialize, Serialize, Clone)]
 #[serde(rename_all = "kebab-case", tag = "type")]
 pub enum Payload {
     #[serde(rename_all = "camelCase")]
     Json {
-        value_field_path: String,
+        value_field_path: Option<String>,
         timestamp_field_path: Option<String>,
+        #[serde(default)]
+        fields: Vec<JsonField>,
     },
 }
 
-#[derive(Debug, Deserialize)]
+/// What to do with retained messages, which the broker sends on subscribing
+/// and which would otherwise be stored again with the current time.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum RetainedPolicy {
+    Ignore,
+    #[default]
+    Store,
+    /// Store only the first retained message seen on each topic.
+    Once,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct Mapping {
     pub topic: String,
+    pub qos: Option<u8>,
+    pub content_type: Option<String>,
     pub payload: Option<Payload>,
-    pub field_name: String,
-    pub value_type: ValueType,
+    pub measurement: Option<String>,
+    pub field_name: Option<String>,
+    pub value_type: Option<ValueType>,
     pub tags: HashMap<String, TagValue>,
+    pub databases: Option<Vec<String>>,
+    pub brokers: Option<Vec<String>>,
+    #[serde(default)]
+    pub retained: RetainedPolicy,
+    #[serde(default)]
+    pub order: i32,
+    pub stop: Option<bool>,
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum MatchMode {
+    #[default]
+    First,
+    All,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct Config {
     pub log_level: Option<LevelFilter>,
-    pub mqtt: MqttConfig,
+    #[serde(default)]
+    pub match_mode: MatchMode,
+    /// A single broker; the same as giving it as the only one in `brokers`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mqtt: Option<MqttConfig>,
+    #[serde(default)]
+    pub brokers: Vec<MqttConfig>,
     pub databases: Vec<Database>,
     pub mappings: Vec<Mapping>,
 }
@@ -106,7 +562,325 @@ impl Config {
         let mut f = File::open(filename)?;
         let mut contents = String::new();
         f.read_to_string(&mut contents)?;
-        let config: Config = from_str(&contents)?;
+        let mut value: YamlValue = from_str(&contents)?;
+        resolve_value(&mut value, "")?;
+        let mut config: Config = from_value(value)?;
+
+        match config.mqtt.take() {
+            Some(_) if !config.brokers.is_empty() => Err(anyhow!("Only one of 'mqtt' and 'brokers' may be given"))?,
+            Some(mqtt) => config.brokers.push(mqtt),
+            None if config.brokers.is_empty() => Err(anyhow!("No MQTT broker is configured"))?,
+            None => (),
+        }
+
+        let mut broker_names = HashSet::new();
+        for broker in config.brokers.iter() {
+            broker
+                .validate()
+                .map_err(|err| anyhow!("Broker '{}': {}", broker.name(), err))?;
+            if !broker_names.insert(broker.name()) {
+                Err(anyhow!("Broker name '{}' is used more than once", broker.name()))?;
+            }
+        }
+
+        let mut database_names = HashSet::new();
+        let mut spool_paths = HashSet::new();
+        for (i, database) in config.databases.iter().enumerate() {
+            database.validate().map_err(|err| anyhow!("databases[{}]: {}", i, err))?;
+            if let Some(name) = &database.name {
+                if !database_names.insert(name) {
+                    Err(anyhow!("Database name '{}' is used more than once", name))?;
+                }
+            }
+            if let Some(spool) = &database.spool {
+                if !spool_paths.insert(&spool.path) {
+                    Err(anyhow!("Spool path '{}' is used by more than one database", spool.path))?;
+                }
+            }
+        }
+
+        // Every tag gets a column of its own in each SQL table.
+        for table in config.databases.iter().flat_map(Database::sql_table) {
+            let columns = [&table.time_column, &table.field_column, &table.value_column];
+            for tag_name in config.mappings.iter().flat_map(|mapping| mapping.tags.keys()) {
+                if columns.contains(&tag_name) {
+                    Err(anyhow!("Tag '{}' has the same name as a column of table '{}'", tag_name, table.name))?;
+                }
+            }
+        }
+
         Ok(config)
     }
 }
+
+/// Keys whose value may instead be read from a file, by giving
+/// e.g. `passwordFile: /run/secrets/influx` in place of `password`.
+const SECRET_KEYS: &[&str] = &["username", "password", "token", "connection"];
+
+/// Sections, with sequence indexes left out, where those keys may be read
+/// from a file.  Elsewhere, e.g. in mapping tags or WebSocket headers, a key
+/// like `tokenFile` is just a name.
+const SECRET_SECTIONS: &[&str] = &["mqtt.auth", "brokers.auth", "databases", "databases.auth"];
+
+fn config_section(path: &str) -> String {
+    path.split('[')
+        .map(|part| part.split_once(']').map_or(part, |(_, rest)| rest))
+        .collect()
+}
+
+fn resolve_value(value: &mut YamlValue, path: &str) -> anyhow::Result<()> {
+    match value {
+        YamlValue::String(s) => *s = expand_env(s, path)?,
+        YamlValue::Sequence(seq) => {
+            for (i, item) in seq.iter_mut().enumerate() {
+                resolve_value(item, &format!("{}[{}]", path, i))?;
+            }
+        }
+        YamlValue::Mapping(mapping) => resolve_mapping(mapping, path)?,
+        _ => (),
+    }
+    Ok(())
+}
+
+fn resolve_mapping(mapping: &mut YamlMapping, path: &str) -> anyhow::Result<()> {
+    let allows_secret_files = SECRET_SECTIONS.contains(&config_section(path).as_str());
+    let original = std::mem::take(mapping);
+    for (key, mut value) in original.clone() {
+        let key_path = match key.as_str() {
+            Some(key) if path.is_empty() => key.to_string(),
+            Some(key) => format!("{}.{}", path, key),
+            None => path.to_string(),
+        };
+        resolve_value(&mut value, &key_path)?;
+
+        let secret_key = key
+            .as_str()
+            .filter(|_| allows_secret_files)
+            .and_then(|key| key.strip_suffix("File"))
+            .filter(|key| SECRET_KEYS.contains(key));
+        if let Some(secret_key) = secret_key {
+            if original.contains_key(&YamlValue::String(secret_key.to_string())) {
+                Err(anyhow!("{}: only one of '{}' and '{}File' may be given", key_path, secret_key, secret_key))?;
+            }
+            let filename = value
+                .as_str()
+                .ok_or_else(|| anyhow!("{}: file name must be a string", key_path))?;
+            let contents = fs::read_to_string(filename)
+                .map_err(|err| anyhow!("{}: unable to read '{}': {}", key_path, filename, err))?;
+            let secret = contents.strip_suffix('\n').unwrap_or(&contents);
+            let secret = secret.strip_suffix('\r').unwrap_or(secret);
+            mapping.insert(YamlValue::String(secret_key.to_string()), YamlValue::String(secret.to_string()));
+        } else {
+            mapping.insert(key, value);
+        }
+    }
+    Ok(())
+}
+
+/// Expands `${VAR}` references to environment variables.  A literal
+/// `${` can be written as `$${`.
+fn expand_env(s: &str, path: &str) -> anyhow::Result<String> {
+    let mut expanded = String::with_capacity(s.len());
+    let mut rest = s;
+    while let Some(start) = rest.find('$') {
+        expanded.push_str(&rest[..start]);
+        rest = &rest[start..];
+        if let Some(after) = rest.strip_prefix("$${") {
+            expanded.push_str("${");
+            rest = after;
+        } else if let Some(after) = rest.strip_prefix("${") {
+            // The value may well be a secret, so errors only say where in it
+            // the problem is.
+            let position = s[..s.len() - rest.len()].chars().count() + 1;
+            let end = after
+                .find('}')
+                .ok_or_else(|| anyhow!("{}: unterminated '${{' at character {}", path, position))?;
+            let name = &after[..end];
+            if name.is_empty() {
+                Err(anyhow!("{}: empty environment variable reference at character {}", path, position))?;
+            }
+            let value = env::var(name).map_err(|err| match err {
+                env::VarError::NotPresent => anyhow!("{}: environment variable '{}' is not set", path, name),
+                env::VarError::NotUnicode(_) => anyhow!("{}: environment variable '{}' is not valid UTF-8", path, name),
+            })?;
+            expanded.push_str(&value);
+            rest = &after[end + 1..];
+        } else {
+            expanded.push('$');
+            rest = &rest[1..];
+        }
+    }
+    expanded.push_str(rest);
+    Ok(expanded)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_util::{mqtt_config, temp_path};
+
+    fn resolve(yaml: &str) -> anyhow::Result<YamlValue> {
+        let mut value: YamlValue = from_str(yaml)?;
+        resolve_value(&mut value, "")?;
+        Ok(value)
+    }
+
+    fn parse_config(yaml: &str) -> anyhow::Result<Config> {
+        let path = temp_path(&format!("config-{:?}.yaml", std::thread::current().id()));
+        fs::write(&path, yaml)?;
+        let config = Config::parse(&path);
+        fs::remove_file(&path)?;
+        config
+    }
+
+    #[test]
+    fn env_substitution() -> anyhow::Result<()> {
+        env::set_var("MQTT2DB_TEST_PASSWORD", "s3cret");
+        env::remove_var("MQTT2DB_TEST_UNSET");
+
+        let value = resolve("auth: { password: 'pre-${MQTT2DB_TEST_PASSWORD}-post' }")?;
+        assert_eq!(value["auth"]["password"].as_str(), Some("pre-s3cret-post"));
+
+        let value = resolve("topics: ['$$1/$${MQTT2DB_TEST_PASSWORD}']")?;
+        assert_eq!(value["topics"][0].as_str(), Some("$$1/${MQTT2DB_TEST_PASSWORD}"));
+
+        let err = resolve("auth: { password: '${MQTT2DB_TEST_UNSET}' }").unwrap_err();
+        assert_eq!(err.to_string(), "auth.password: environment variable 'MQTT2DB_TEST_UNSET' is not set");
+        let err = resolve("auth: { password: 'ab${cd' }").unwrap_err();
+        assert_eq!(err.to_string(), "auth.password: unterminated '${' at character 3");
+        let err = resolve("auth: { password: 'ab${}cd' }").unwrap_err();
+        assert_eq!(err.to_string(), "auth.password: empty environment variable reference at character 3");
+
+        Ok(())
+    }
+
+    #[test]
+    fn secret_files() -> anyhow::Result<()> {
+        let path = temp_path("secret");
+        fs::write(&path, "t0ken\n")?;
+        let path = path.as_str();
+
+        let value = resolve(&format!("databases: [{{ tokenFile: '{}' }}]", path))?;
+        assert_eq!(value["databases"][0]["token"].as_str(), Some("t0ken"));
+        assert!(value["databases"][0].get("tokenFile").is_none());
+
+        let value = resolve(&format!("brokers: [{{ auth: {{ passwordFile: '{}' }} }}]", path))?;
+        assert_eq!(value["brokers"][0]["auth"]["password"].as_str(), Some("t0ken"));
+
+        // Other *File keys are left alone.
+        let value = resolve(&format!("mqtt: {{ auth: {{ certFile: '{}' }} }}", path))?;
+        assert_eq!(value["mqtt"]["auth"]["certFile"].as_str(), Some(path));
+
+        // So are secret-looking names chosen by the user.
+        let value = resolve(&format!("mappings: [{{ tags: {{ tokenFile: '{}' }} }}]", path))?;
+        assert_eq!(value["mappings"][0]["tags"]["tokenFile"].as_str(), Some(path));
+        let value = resolve(&format!("mqtt: {{ websocket: {{ headers: {{ passwordFile: '{}' }} }} }}", path))?;
+        assert_eq!(value["mqtt"]["websocket"]["headers"]["passwordFile"].as_str(), Some(path));
+
+        assert!(resolve(&format!("mqtt: {{ auth: {{ token: foo, tokenFile: '{}' }} }}", path)).is_err());
+        assert!(resolve("mqtt: { auth: { passwordFile: /nonexistent/mqtt2db/secret } }").is_err());
+
+        fs::remove_file(path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn protocol_version_parsing() -> anyhow::Result<()> {
+        let parse = |yaml: &str| -> anyhow::Result<ProtocolVersion> { Ok(mqtt_config(yaml)?.protocol_version) };
+
+        assert_eq!(parse("")?, ProtocolVersion::V311);
+        assert_eq!(parse("protocolVersion: 3.1.1")?, ProtocolVersion::V311);
+        assert_eq!(parse("protocolVersion: 5")?, ProtocolVersion::V5);
+        assert_eq!(parse("protocolVersion: '5'")?, ProtocolVersion::V5);
+        assert!(parse("protocolVersion: 3").is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn shared_subscriptions() -> anyhow::Result<()> {
+        let parse = |yaml: &str| -> anyhow::Result<MqttConfig> {
+            let config = mqtt_config(yaml)?;
+            config.validate()?;
+            Ok(config)
+        };
+
+        assert_eq!(parse("")?.subscription_filter("a/+"), "a/+");
+        assert_eq!(parse("sharedGroup: db")?.subscription_filter("a/+"), "$share/db/a/+");
+        assert!(parse("sharedGroup: ''").is_err());
+        assert!(parse("sharedGroup: a/b").is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn websocket_validation() -> anyhow::Result<()> {
+        let validate = |yaml: &str| mqtt_config(yaml)?.validate();
+
+        assert!(validate("websocket: {}").is_ok());
+        assert!(validate("websocket: { path: /ws, headers: { Authorization: Bearer t0ken } }").is_ok());
+        let err = validate("websocket: { path: mqtt }").unwrap_err();
+        assert_eq!(err.to_string(), "WebSocket path 'mqtt' must start with '/'");
+        assert!(validate("websocket: { headers: { 'bad name': x } }").is_err());
+        assert!(validate("websocket: { headers: { X-Token: \"a\\nb\" } }").is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn tag_column_clash() -> anyhow::Result<()> {
+        let parse = |tag_name: &str| {
+            parse_config(&format!(
+                r#"
+                mqtt: {{ host: h, port: 1883, clientId: c }}
+                databases:
+                  - {{ type: sqlite, path: a.db, table: {{ name: readings, valueColumn: reading }} }}
+                mappings:
+                  - {{ topic: a/+, fieldName: value, valueType: float, tags: {{ {}: {{ type: text, value: $1 }} }} }}
+                "#,
+                tag_name
+            ))
+        };
+
+        assert!(parse("room").is_ok());
+        assert!(parse("value").is_ok());
+        let err = parse("reading").unwrap_err();
+        assert_eq!(err.to_string(), "Tag 'reading' has the same name as a column of table 'readings'");
+        assert!(parse("time").is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn database_validation() -> anyhow::Result<()> {
+        let parse = |database: &str| {
+            parse_config(&format!(
+                "{{ mqtt: {{ host: h, port: 1883, clientId: c }}, databases: [{}], mappings: [] }}",
+                database
+            ))
+        };
+        let postgres = |connection: &str| {
+            parse(&format!("{{ type: postgres, connection: '{}', table: {{ name: readings }} }}", connection))
+        };
+
+        assert!(postgres("host=localhost user=mqtt2db").is_ok());
+        assert!(postgres("host=localhost sslmode=prefer").is_ok());
+        let err = postgres("host=localhost sslmode=require").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "databases[0]: TLS connections to PostgreSQL are not supported; use sslmode=disable or prefer"
+        );
+        assert!(postgres("host=localhost port=nope").is_err());
+
+        assert!(parse("{ type: sqlite, path: a.db, table: { name: readings } }").is_ok());
+        let err = parse("{ type: sqlite, path: a.db, table: { name: readings, hypertable: true } }").unwrap_err();
+        assert_eq!(err.to_string(), "databases[0]: Hypertables are not supported by SQLite");
+
+        let spooled = |path: &str| format!("{{ type: sqlite, path: a.db, table: {{ name: r }}, spool: {{ path: {} }} }}", path);
+        assert!(parse(&format!("{}, {}", spooled("a.spool"), spooled("b.spool"))).is_ok());
+        let err = parse(&format!("{}, {}", spooled("a.spool"), spooled("a.spool"))).unwrap_err();
+        assert_eq!(err.to_string(), "Spool path 'a.spool' is used by more than one database");
+
+        Ok(())
+    }
+}
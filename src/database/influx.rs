@@ -0,0 +1,120 @@
+// mqtt2db -- subscries to MQTT topics and writes to a database
+// Copyright (C) 2021-2022 Brian Tarricone <brian@tarricone.org>
+// 
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+// 
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+// 
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use influxdb::{Query, WriteQuery};
+use std::collections::HashMap;
+use surf::StatusCode;
+
+use super::WriteError;
+
+/// Writes line protocol over HTTP, to either the InfluxDB 1.x `/write`
+/// endpoint or the 2.x `/api/v2/write` endpoint.
+pub struct InfluxClient {
+    http_client: surf::Client,
+    write_url: String,
+    parameters: HashMap<&'static str, String>,
+    token: Option<String>,
+}
+
+impl InfluxClient {
+    pub fn v1(url: &str, db_name: &str, username: Option<&str>, password: Option<&str>) -> InfluxClient {
+        let mut parameters = HashMap::new();
+        parameters.insert("db", db_name.to_string());
+        parameters.insert("precision", "ns".to_string());
+        if let (Some(username), Some(password)) = (username, password) {
+            parameters.insert("u", username.to_string());
+            parameters.insert("p", password.to_string());
+        }
+        InfluxClient {
+            http_client: surf::Client::new(),
+            write_url: format!("{}/write", url.trim_end_matches('/')),
+            parameters,
+            token: None,
+        }
+    }
+
+    pub fn v2(url: &str, org: &str, bucket: &str, token: &str) -> InfluxClient {
+        InfluxClient {
+            http_client: surf::Client::new(),
+            write_url: format!("{}/api/v2/write", url.trim_end_matches('/')),
+            parameters: HashMap::from([
+                ("org", org.to_string()),
+                ("bucket", bucket.to_string()),
+                ("precision", "ns".to_string()),
+            ]),
+            token: Some(token.to_string()),
+        }
+    }
+
+    pub async fn write(&self, queries: &[WriteQuery]) -> Result<(), WriteError> {
+        let body = queries
+            .iter()
+            .map(|query| query.build().map(|valid_query| valid_query.get()))
+            .collect::<Result<Vec<String>, _>>()
+            .map_err(|err| WriteError::Permanent(anyhow!("Invalid query: {}", err)))?
+            .join("\n");
+
+        let mut request = self
+            .http_client
+            .post(&self.write_url)
+            .query(&self.parameters)
+            .map_err(|err| WriteError::Permanent(anyhow!("Failed to build URL: {}", err)))?;
+        if let Some(token) = &self.token {
+            request = request.header("Authorization", format!("Token {}", token));
+        }
+
+        let mut response = request
+            .body_string(body)
+            .await
+            .map_err(|err| WriteError::Transient(anyhow!("Connection error: {}", err)))?;
+
+        let status = response.status();
+        if status.is_success() {
+            Ok(())
+        } else {
+            let message = response.body_string().await.unwrap_or_default();
+            Err(classify_status(status, anyhow!("InfluxDB returned {}: {}", status, message)))
+        }
+    }
+}
+
+/// Server errors and rate limiting are worth retrying; any other error
+/// status means the write itself is bad.
+fn classify_status(status: StatusCode, err: anyhow::Error) -> WriteError {
+    if status.is_server_error() || status == StatusCode::TooManyRequests {
+        WriteError::Transient(err)
+    } else {
+        WriteError::Permanent(err)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn status_classification() {
+        let transient = |status| matches!(classify_status(status, anyhow!("failed")), WriteError::Transient(_));
+
+        assert!(transient(StatusCode::InternalServerError));
+        assert!(transient(StatusCode::ServiceUnavailable));
+        assert!(transient(StatusCode::TooManyRequests));
+        assert!(!transient(StatusCode::BadRequest));
+        assert!(!transient(StatusCode::Unauthorized));
+        assert!(!transient(StatusCode::NotFound));
+        assert!(!transient(StatusCode::PayloadTooLarge));
+    }
+}
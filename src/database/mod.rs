@@ -0,0 +1,861 @@
+// mqtt2db -- subscries to MQTT topics and writes to a database
+// Copyright (C) 2021-2022 Brian Tarricone <brian@tarricone.org>
+// 
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+// 
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+// 
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use chrono::{DateTime, TimeZone, Utc};
+use influxdb::{InfluxDbWriteable, Timestamp, Type, WriteQuery};
+use std::fmt;
+use std::time::Duration;
+use std::sync::Arc;
+use tokio::sync::{mpsc, oneshot, Mutex};
+use tokio::time::{sleep, timeout_at, Instant};
+
+use crate::backoff::backoff_delay;
+use crate::config::{BatchConfig, Database as ConfigDatabase, DatabaseType, RetryConfig, SqlTable, UserAuth};
+use crate::value::ValueType;
+
+mod influx;
+mod postgres;
+mod spool;
+mod sqlite;
+
+use influx::InfluxClient;
+use postgres::PostgresClient;
+use spool::Spool;
+use sqlite::SqliteClient;
+
+#[derive(Clone, Debug)]
+pub struct Point {
+    /// Overrides the database's measurement; only used by InfluxDB.
+    pub measurement: Option<String>,
+    pub timestamp: u128,
+    pub fields: Vec<(String, Type)>,
+    pub tags: Vec<(String, Type)>,
+}
+
+impl Point {
+    fn to_query(&self, measurement: &str) -> WriteQuery {
+        let measurement = self.measurement.as_deref().unwrap_or(measurement);
+        let mut query = Timestamp::Nanoseconds(self.timestamp).into_query(measurement);
+        for (name, value) in self.fields.iter() {
+            query = query.add_field(name, value.clone());
+        }
+        for (name, value) in self.tags.iter() {
+            query = query.add_tag(name, value.clone());
+        }
+        query
+    }
+}
+
+#[derive(Debug)]
+pub enum SqlValue {
+    Boolean(bool),
+    Float(f64),
+    Integer(i64),
+    Text(String),
+}
+
+impl SqlValue {
+    pub fn convert(value: &Type, value_type: ValueType) -> anyhow::Result<SqlValue> {
+        match (value_type, value) {
+            (ValueType::Boolean, Type::Boolean(b)) => Ok(SqlValue::Boolean(*b)),
+            (ValueType::Float, Type::Float(f)) => Ok(SqlValue::Float(*f)),
+            (ValueType::Float, Type::SignedInteger(i)) => Ok(SqlValue::Float(*i as f64)),
+            (ValueType::Float, Type::UnsignedInteger(u)) => Ok(SqlValue::Float(*u as f64)),
+            (ValueType::Float, Type::Boolean(b)) => Ok(SqlValue::Float(if *b { 1.0 } else { 0.0 })),
+            (ValueType::SignedInteger | ValueType::UnsignedInteger, Type::SignedInteger(i)) => {
+                Ok(SqlValue::Integer(*i))
+            }
+            (ValueType::SignedInteger | ValueType::UnsignedInteger, Type::UnsignedInteger(u)) => {
+                i64::try_from(*u)
+                    .map(SqlValue::Integer)
+                    .map_err(|_| anyhow!("Value {} is too large to be stored as an integer", u))
+            }
+            (ValueType::SignedInteger | ValueType::UnsignedInteger, Type::Boolean(b)) => {
+                Ok(SqlValue::Integer(*b as i64))
+            }
+            (ValueType::Text, Type::Text(s)) => Ok(SqlValue::Text(s.clone())),
+            (ValueType::Text, Type::Boolean(b)) => Ok(SqlValue::Text(b.to_string())),
+            (ValueType::Text, Type::Float(f)) => Ok(SqlValue::Text(f.to_string())),
+            (ValueType::Text, Type::SignedInteger(i)) => Ok(SqlValue::Text(i.to_string())),
+            (ValueType::Text, Type::UnsignedInteger(u)) => Ok(SqlValue::Text(u.to_string())),
+            (other_type, other_value) => Err(anyhow!(
+                "Unable to store value {:?} in a column of type {}",
+                other_value,
+                other_type
+            )),
+        }
+    }
+}
+
+fn quote_identifier(identifier: &str) -> String {
+    format!("\"{}\"", identifier.replace('"', "\"\""))
+}
+
+fn sql_type(value_type: ValueType) -> &'static str {
+    match value_type {
+        ValueType::Boolean => "BOOLEAN",
+        ValueType::Float => "DOUBLE PRECISION",
+        ValueType::SignedInteger | ValueType::UnsignedInteger => "BIGINT",
+        ValueType::Text => "TEXT",
+    }
+}
+
+/// One row of a SQL table: a single field of a point.
+struct SqlRow {
+    time: DateTime<Utc>,
+    field_name: String,
+    value: SqlValue,
+    tag_values: Vec<Option<String>>,
+}
+
+/// The layout of a SQL table points are written to, shared by PostgreSQL
+/// and SQLite: a row per field, with the tags in columns of their own.
+struct SqlSchema {
+    table_name: String,
+    /// The time, field and value columns, and then one per tag.
+    columns: Vec<String>,
+    value_type: ValueType,
+    create: bool,
+}
+
+impl SqlSchema {
+    fn new(table: &SqlTable, tag_names: &[String]) -> SqlSchema {
+        let mut columns = vec![
+            table.time_column.clone(),
+            table.field_column.clone(),
+            table.value_column.clone(),
+        ];
+        columns.extend(tag_names.iter().cloned());
+        SqlSchema {
+            table_name: table.name.clone(),
+            columns,
+            value_type: table.value_type,
+            create: table.create,
+        }
+    }
+
+    fn tag_names(&self) -> &[String] {
+        &self.columns[3..]
+    }
+
+    /// Creates the table if mqtt2db manages it and it doesn't exist yet.
+    fn create_sql(&self, time_type: &str) -> Option<String> {
+        if !self.create {
+            return None;
+        }
+        let mut column_defs = vec![
+            format!("{} {} NOT NULL", quote_identifier(&self.columns[0]), time_type),
+            format!("{} TEXT NOT NULL", quote_identifier(&self.columns[1])),
+            format!("{} {}", quote_identifier(&self.columns[2]), sql_type(self.value_type)),
+        ];
+        column_defs.extend(self.tag_names().iter().map(|tag_name| format!("{} TEXT", quote_identifier(tag_name))));
+        Some(format!(
+            "CREATE TABLE IF NOT EXISTS {} ({})",
+            quote_identifier(&self.table_name),
+            column_defs.join(", ")
+        ))
+    }
+
+    /// Given the columns the table has, adds any it lacks when mqtt2db
+    /// manages the table (which may predate tags added to the config since),
+    /// and otherwise fails, naming them.
+    fn alter_sql(&self, existing_columns: &[String]) -> anyhow::Result<Vec<String>> {
+        if existing_columns.is_empty() && !self.create {
+            Err(anyhow!(
+                "Table '{}' does not exist; create it, or set 'create: true'",
+                self.table_name
+            ))?;
+        }
+        let missing_columns = self
+            .columns
+            .iter()
+            .filter(|column| !existing_columns.contains(column))
+            .collect::<Vec<&String>>();
+        if !missing_columns.is_empty() && !self.create {
+            Err(anyhow!(
+                "Table '{}' is missing columns {}; add them, or set 'create: true'",
+                self.table_name,
+                missing_columns.iter().map(|column| column.as_str()).collect::<Vec<&str>>().join(", ")
+            ))?;
+        }
+        Ok(missing_columns
+            .iter()
+            .map(|column| {
+                format!(
+                    "ALTER TABLE {} ADD COLUMN {} TEXT",
+                    quote_identifier(&self.table_name),
+                    quote_identifier(column)
+                )
+            })
+            .collect())
+    }
+
+    /// The insert statement, with placeholders numbered after `prefix`.
+    fn insert_sql(&self, placeholder_prefix: char) -> String {
+        let placeholders = (1..=self.columns.len())
+            .map(|n| format!("{}{}", placeholder_prefix, n))
+            .collect::<Vec<String>>();
+        format!(
+            "INSERT INTO {} ({}) VALUES ({})",
+            quote_identifier(&self.table_name),
+            self.columns.iter().map(|column| quote_identifier(column)).collect::<Vec<String>>().join(", "),
+            placeholders.join(", ")
+        )
+    }
+
+    fn to_rows(&self, point: &Point) -> anyhow::Result<Vec<SqlRow>> {
+        let time = Utc.timestamp_nanos(point.timestamp as i64);
+        let tag_values = self
+            .tag_names()
+            .iter()
+            .map(|tag_name| {
+                point
+                    .tags
+                    .iter()
+                    .find(|(name, _)| name == tag_name)
+                    .map(|(_, value)| value.to_string())
+            })
+            .collect::<Vec<Option<String>>>();
+
+        point
+            .fields
+            .iter()
+            .map(|(field_name, value)| {
+                Ok(SqlRow {
+                    time,
+                    field_name: field_name.clone(),
+                    value: SqlValue::convert(value, self.value_type)?,
+                    tag_values: tag_values.clone(),
+                })
+            })
+            .collect()
+    }
+
+    /// The rows for a batch of points, leaving out any point whose values
+    /// don't fit the table.
+    fn rows(&self, points: &[Point]) -> Vec<SqlRow> {
+        points
+            .iter()
+            .flat_map(|point| match self.to_rows(point) {
+                Ok(rows) => rows,
+                Err(err) => {
+                    warn!("Dropping point {:?}: {}", point, err);
+                    Vec::new()
+                }
+            })
+            .collect()
+    }
+}
+
+#[derive(Debug)]
+pub enum WriteError {
+    /// The database couldn't be reached or is temporarily overloaded; the
+    /// same write may succeed later.
+    Transient(anyhow::Error),
+    /// The database rejected the write, and will keep doing so.
+    Permanent(anyhow::Error),
+}
+
+impl fmt::Display for WriteError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WriteError::Transient(err) | WriteError::Permanent(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl std::error::Error for WriteError {}
+
+impl WriteError {
+    /// A copy of the error, for reporting one failed batch to each of the
+    /// writers that contributed to it.
+    fn duplicate(&self) -> WriteError {
+        match self {
+            WriteError::Transient(err) => WriteError::Transient(anyhow!("{}", err)),
+            WriteError::Permanent(err) => WriteError::Permanent(anyhow!("{}", err)),
+        }
+    }
+}
+
+/// Tells the writer of a point what became of it.
+type WriteResultSender = oneshot::Sender<Result<(), WriteError>>;
+
+enum DatabaseClient {
+    Influx {
+        client: Box<InfluxClient>,
+        measurement: String,
+    },
+    Postgres(Box<PostgresClient>),
+    Sqlite(SqliteClient),
+}
+
+impl DatabaseClient {
+    async fn write(&self, points: &[Point]) -> Result<(), WriteError> {
+        match self {
+            DatabaseClient::Influx { client, measurement } => {
+                client
+                    .write(
+                        &points
+                            .iter()
+                            .map(|point| point.to_query(measurement))
+                            .collect::<Vec<WriteQuery>>(),
+                    )
+                    .await
+            }
+            DatabaseClient::Postgres(client) => client.write(points).await,
+            DatabaseClient::Sqlite(client) => client.write(points).await,
+        }
+    }
+
+    async fn write_with_retry(&self, points: &[Point], retry: &RetryConfig, max_attempts: u32) -> Result<(), WriteError> {
+        let mut attempt = 1;
+        loop {
+            match self.write(points).await {
+                Err(WriteError::Transient(err)) if attempt < max_attempts => {
+                    let delay = retry_delay(retry, attempt);
+                    warn!(
+                        "Write attempt {} of {} failed; retrying in {:?}: {}",
+                        attempt, max_attempts, delay, err
+                    );
+                    sleep(delay).await;
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Writes a batch of points, and if the database rejects it, splits it
+    /// in half until the points it objects to are found, so that those are
+    /// the only ones dropped.  Gives the result for each point; once a write
+    /// fails in a way that may clear up, the rest aren't tried, and share
+    /// that failure.
+    async fn write_batch(&self, points: &[Point], retry: &RetryConfig, max_attempts: u32) -> Vec<Result<(), WriteError>> {
+        let mut results = Vec::with_capacity(points.len());
+        let mut pending = vec![(0, points.len())];
+        while let Some((start, end)) = pending.pop() {
+            match self.write_with_retry(&points[start..end], retry, max_attempts).await {
+                Err(WriteError::Permanent(_)) if end - start > 1 => {
+                    let middle = start + (end - start) / 2;
+                    pending.push((middle, end));
+                    pending.push((start, middle));
+                }
+                Err(WriteError::Permanent(err)) => {
+                    warn!("Dropping point rejected by the database: {}", err);
+                    results.push(Err(WriteError::Permanent(err)));
+                }
+                Err(WriteError::Transient(err)) => {
+                    let err = WriteError::Transient(err);
+                    results.extend((start..points.len()).map(|_| Err(err.duplicate())));
+                    break;
+                }
+                Ok(_) => results.extend((start..end).map(|_| Ok(()))),
+            }
+        }
+        results
+    }
+}
+
+/// Where the points that failed to be written, if any, start, and why.
+/// These are always the last ones, as `write_batch` stops at the first
+/// such failure.
+fn first_failed(results: &[Result<(), WriteError>]) -> Option<(usize, &WriteError)> {
+    results.iter().enumerate().find_map(|(i, result)| match result {
+        Err(err @ WriteError::Transient(_)) => Some((i, err)),
+        _ => None,
+    })
+}
+
+fn retry_delay(retry: &RetryConfig, attempt: u32) -> Duration {
+    backoff_delay(retry.initial_delay, retry.max_delay, attempt)
+}
+
+pub struct Database {
+    pub name: Option<String>,
+    point_sender: mpsc::UnboundedSender<(Point, WriteResultSender)>,
+    retry: RetryConfig,
+    /// The spool's path, and the spool itself, which is shared with the
+    /// database replacing this one on a reload, so that their writers never
+    /// work on the same file behind each other's backs.
+    spool: Option<(String, Arc<Mutex<Spool>>)>,
+}
+
+impl Database {
+    /// Queues a point, and waits until the batch it ends up in has been
+    /// written to the database, or failing that, to the spool.
+    pub async fn write(&self, point: Point) -> Result<(), WriteError> {
+        let stopped = || WriteError::Transient(anyhow!("Database writer has stopped"));
+        let (result_sender, result_receiver) = oneshot::channel();
+        self.point_sender
+            .send((point, result_sender))
+            .map_err(|_| stopped())?;
+        result_receiver.await.map_err(|_| stopped())?
+    }
+
+    /// Like `write`, but instead of giving up on a transient failure, keeps
+    /// trying until the point is stored or rejected.
+    pub async fn write_until_stored(&self, point: Point) -> Result<(), WriteError> {
+        let mut attempt = 1;
+        loop {
+            match self.write(point.clone()).await {
+                Err(WriteError::Transient(err)) => {
+                    let delay = retry_delay(&self.retry, attempt);
+                    debug!("Failed to store point; trying again in {:?}: {}", delay, err);
+                    sleep(delay).await;
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+async fn replay_spool(
+    client: &DatabaseClient,
+    spool: &mut Spool,
+    batch: &BatchConfig,
+    retry: &RetryConfig,
+    max_attempts: u32,
+) -> Result<(), WriteError> {
+    let points = spool.read().await.map_err(WriteError::Transient)?;
+    let max_size = batch.max_size.max(1);
+    for (i, chunk) in points.chunks(max_size).enumerate() {
+        let mut results = client.write_batch(chunk, retry, max_attempts).await;
+        if let Some((failed, _)) = first_failed(&results) {
+            spool
+                .replace(&points[i * max_size + failed..])
+                .await
+                .map_err(WriteError::Transient)?;
+            return results.swap_remove(failed);
+        }
+        debug!("Replayed {} spooled points to database", chunk.len());
+    }
+    spool.replace(&[]).await.map_err(WriteError::Transient)?;
+    info!("Finished replaying {} spooled points", points.len());
+    Ok(())
+}
+
+async fn run_writer(
+    client: DatabaseClient,
+    batch: BatchConfig,
+    retry: RetryConfig,
+    spool: Option<Arc<Mutex<Spool>>>,
+    mut point_receiver: mpsc::UnboundedReceiver<(Point, WriteResultSender)>,
+) {
+    let max_size = batch.max_size.max(1);
+
+    // After a batch has used up all its retries, the database is probably
+    // down for a while; until a write goes through again, make only a single
+    // attempt per batch so the backlog doesn't pile up behind the backoff.
+    let mut degraded = false;
+
+    // A non-empty spool is replayed on its own schedule too, so spooled
+    // points don't wait for new traffic once the database is back.  A spool
+    // left over from a previous run gets its first attempt right away.
+    let mut replay_at = match &spool {
+        Some(spool) if !spool.lock().await.is_empty() => Some(Instant::now()),
+        _ => None,
+    };
+    let mut replay_failures = 0;
+
+    // Wait for a first point, and then keep collecting more until the batch
+    // is full or old enough.  Once all senders are gone, whatever is left is
+    // flushed before exiting.
+    loop {
+        let received = match (replay_at, &spool) {
+            (Some(at), Some(spool)) => match timeout_at(at, point_receiver.recv()).await {
+                Ok(received) => received,
+                Err(_) => {
+                    let mut spool = spool.lock().await;
+                    match replay_spool(&client, &mut spool, &batch, &retry, 1).await {
+                        Ok(_) => {
+                            degraded = false;
+                            replay_failures = 0;
+                            replay_at = None;
+                        }
+                        Err(err) => {
+                            degraded = true;
+                            replay_failures += 1;
+                            let delay = retry_delay(&retry, replay_failures);
+                            warn!("Failed to replay spooled points; retrying in {:?}: {}", delay, err);
+                            replay_at = Some(Instant::now() + delay);
+                        }
+                    }
+                    continue;
+                }
+            },
+            _ => point_receiver.recv().await,
+        };
+        let (first_point, first_result_sender) = match received {
+            Some(received) => received,
+            None => break,
+        };
+
+        let deadline = Instant::now() + batch.max_age;
+        let mut points = vec![first_point];
+        let mut result_senders = vec![first_result_sender];
+        while points.len() < max_size {
+            match timeout_at(deadline, point_receiver.recv()).await {
+                Ok(Some((point, result_sender))) => {
+                    points.push(point);
+                    result_senders.push(result_sender);
+                }
+                Ok(None) | Err(_) => break,
+            }
+        }
+
+        let max_attempts = if degraded { 1 } else { retry.max_attempts.max(1) };
+
+        // Hold on to the spool until this batch is done with it, so that the
+        // spool and this batch go out in order.
+        let mut spool = match &spool {
+            Some(spool) => Some(spool.lock().await),
+            None => None,
+        };
+
+        // Anything already in the spool is older than this batch, so it has
+        // to go out first; if it can't, this batch joins it in the spool.
+        let mut results = match spool.as_mut() {
+            Some(spool) if !spool.is_empty() => {
+                match replay_spool(&client, spool, &batch, &retry, max_attempts).await {
+                    Ok(_) => client.write_batch(&points, &retry, max_attempts).await,
+                    Err(err) => points.iter().map(|_| Err(err.duplicate())).collect(),
+                }
+            }
+            _ => client.write_batch(&points, &retry, max_attempts).await,
+        };
+
+        let failed = first_failed(&results);
+        degraded = failed.is_some();
+        if degraded {
+            replay_failures += 1;
+        } else {
+            replay_failures = 0;
+        }
+        let written = results.iter().filter(|result| result.is_ok()).count();
+        if written > 0 {
+            debug!("Wrote {} points to database", written);
+        }
+        if let Some((failed, err)) = failed {
+            let failed_points = &points[failed..];
+            match spool.as_mut() {
+                Some(spool) => {
+                    warn!("Failed to write {} points to DB; spooling: {}", failed_points.len(), err);
+                    let spooled = spool.append(failed_points).await.map_err(|err| {
+                        warn!("Failed to spool {} points: {}", failed_points.len(), err);
+                        WriteError::Transient(err)
+                    });
+                    for result in results[failed..].iter_mut() {
+                        *result = spooled.as_ref().map_err(WriteError::duplicate).copied();
+                    }
+                }
+                None => warn!("Failed to write {} points to DB: {}", failed_points.len(), err),
+            }
+        }
+
+        for (result_sender, result) in result_senders.into_iter().zip(results) {
+            // The writer may have given up waiting, which is fine.
+            let _ = result_sender.send(result);
+        }
+
+        replay_at = match spool {
+            Some(spool) if !spool.is_empty() => {
+                Some(Instant::now() + retry_delay(&retry, replay_failures.max(1)))
+            }
+            _ => None,
+        };
+    }
+}
+
+/// Sets up a database, taking over the spool of any of `previous` (the
+/// databases it replaces) that spools to the same file.
+pub async fn init_db(config: &ConfigDatabase, tag_names: &[String], previous: &[Database]) -> anyhow::Result<Database> {
+    let client = match &config.r#type {
+        DatabaseType::Influxdb { url, auth, db_name, measurement } => {
+            let (username, password) = match auth {
+                Some(UserAuth { username, password }) => (Some(username.as_str()), Some(password.as_str())),
+                None => (None, None),
+            };
+            DatabaseClient::Influx {
+                client: Box::new(InfluxClient::v1(url, db_name, username, password)),
+                measurement: measurement.clone(),
+            }
+        }
+        DatabaseType::Influxdb2 { url, org, bucket, token, measurement } => DatabaseClient::Influx {
+            client: Box::new(InfluxClient::v2(url, org, bucket, token)),
+            measurement: measurement.clone(),
+        },
+        DatabaseType::Postgres { connection, table } => {
+            let client = PostgresClient::new(connection, table, tag_names)?;
+            match client.connect().await {
+                Ok(_) => (),
+                Err(WriteError::Transient(err)) => {
+                    warn!("Unable to connect to PostgreSQL; will retry on next write: {}", err)
+                }
+                Err(WriteError::Permanent(err)) => Err(err)?,
+            }
+            DatabaseClient::Postgres(Box::new(client))
+        }
+        DatabaseType::Sqlite { path, table, wal } => {
+            DatabaseClient::Sqlite(SqliteClient::new(path, table, *wal, tag_names)?)
+        }
+    };
+
+    let previous_spool = |path: &str| {
+        previous
+            .iter()
+            .flat_map(|database| database.spool.as_ref())
+            .find(|(previous_path, _)| previous_path == path)
+            .map(|(_, spool)| Arc::clone(spool))
+    };
+    let spool = match &config.spool {
+        Some(spool_config) => match previous_spool(&spool_config.path) {
+            Some(spool) => {
+                spool.lock().await.configure(spool_config);
+                Some((spool_config.path.clone(), spool))
+            }
+            None => {
+                let spool = Spool::open(spool_config).await?;
+                Some((spool_config.path.clone(), Arc::new(Mutex::new(spool))))
+            }
+        },
+        None => None,
+    };
+
+    let (point_sender, point_receiver) = mpsc::unbounded_channel();
+    tokio::spawn(run_writer(
+        client,
+        config.batch.clone(),
+        config.retry.clone(),
+        spool.as_ref().map(|(_, spool)| Arc::clone(spool)),
+        point_receiver,
+    ));
+
+    Ok(Database {
+        name: config.name.clone(),
+        point_sender,
+        retry: config.retry.clone(),
+        spool,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::config::{DropPolicy, SpoolConfig};
+    use crate::test_util::{mk_point, temp_path};
+    use futures::future::join_all;
+
+    fn count_rows(db_path: &str) -> anyhow::Result<i64> {
+        let conn = rusqlite::Connection::open(db_path)?;
+        Ok(conn.query_row("SELECT COUNT(*) FROM readings", [], |row| row.get(0))?)
+    }
+
+    #[test]
+    fn retry_delay_backoff() {
+        let retry = RetryConfig {
+            max_attempts: 10,
+            initial_delay: Duration::from_millis(100),
+            max_delay: Duration::from_secs(1),
+        };
+
+        for (attempt, full_delay) in [(1, 100), (2, 200), (3, 400), (4, 800), (5, 1000), (40, 1000)] {
+            let full_delay = Duration::from_millis(full_delay);
+            let delay = retry_delay(&retry, attempt);
+            assert!(delay >= full_delay / 2 && delay <= full_delay, "attempt {}: {:?}", attempt, delay);
+        }
+    }
+
+    #[test]
+    fn sql_schema() -> anyhow::Result<()> {
+        let mk_schema = |yaml: &str| -> anyhow::Result<SqlSchema> {
+            let table = serde_yaml::from_str::<SqlTable>(yaml)?;
+            Ok(SqlSchema::new(&table, &["room".to_string(), "sen\"sor".to_string()]))
+        };
+
+        let schema = mk_schema("{ name: readings, create: true, valueType: signed-integer }")?;
+        assert_eq!(
+            schema.create_sql("TIMESTAMPTZ").as_deref(),
+            Some(
+                "CREATE TABLE IF NOT EXISTS \"readings\" (\"time\" TIMESTAMPTZ NOT NULL, \"field\" TEXT NOT NULL, \
+                 \"value\" BIGINT, \"room\" TEXT, \"sen\"\"sor\" TEXT)"
+            )
+        );
+        assert_eq!(
+            schema.insert_sql('$'),
+            "INSERT INTO \"readings\" (\"time\", \"field\", \"value\", \"room\", \"sen\"\"sor\") VALUES ($1, $2, $3, $4, $5)"
+        );
+
+        // A table made before the tags were added gets their columns.
+        let existing_columns = ["time", "field", "value", "room"].map(String::from);
+        assert_eq!(
+            schema.alter_sql(&existing_columns)?,
+            vec!["ALTER TABLE \"readings\" ADD COLUMN \"sen\"\"sor\" TEXT".to_string()]
+        );
+        assert!(schema.alter_sql(&[existing_columns.to_vec(), vec!["sen\"sor".to_string()]].concat())?.is_empty());
+
+        // Unless mqtt2db doesn't manage the table.
+        let schema = mk_schema("{ name: readings }")?;
+        assert_eq!(schema.create_sql("TEXT"), None);
+        let err = schema.alter_sql(&existing_columns).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Table 'readings' is missing columns sen\"sor; add them, or set 'create: true'"
+        );
+        assert!(schema.alter_sql(&[]).is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn batch_flushing() -> anyhow::Result<()> {
+        let mk_config = |path: &str, batch: &str| {
+            serde_yaml::from_str::<ConfigDatabase>(&format!(
+                "{{ type: sqlite, path: '{}', table: {{ name: readings, create: true }}, batch: {} }}",
+                path, batch
+            ))
+        };
+
+        // A full batch goes out without waiting for it to get old.
+        let db_path = temp_path("batch-size.db");
+        let config = mk_config(&db_path, "{ maxSize: 2, maxAge: { secs: 60, nanos: 0 } }")?;
+        let database = init_db(&config, &["room".to_string()], &[]).await?;
+        let (first, second) = tokio::time::timeout(Duration::from_secs(5), async {
+            tokio::join!(database.write(mk_point(1, 1.0)), database.write(mk_point(2, 2.0)))
+        })
+        .await?;
+        first?;
+        second?;
+        assert_eq!(2, count_rows(&db_path)?);
+
+        // A batch that never fills up goes out once it's old enough.
+        let db_path = temp_path("batch-age.db");
+        let config = mk_config(&db_path, "{ maxSize: 100, maxAge: { secs: 0, nanos: 200000000 } }")?;
+        let database = init_db(&config, &["room".to_string()], &[]).await?;
+        let started = Instant::now();
+        tokio::time::timeout(Duration::from_secs(5), database.write(mk_point(1, 1.0))).await??;
+        assert!(started.elapsed() >= Duration::from_millis(200));
+        assert_eq!(1, count_rows(&db_path)?);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn rejected_points_are_isolated() -> anyhow::Result<()> {
+        let mk_config = |path: &str, extra: &str| {
+            rusqlite::Connection::open(path)?.execute_batch(
+                "CREATE TABLE readings (time TEXT NOT NULL, field TEXT NOT NULL, value REAL CHECK (value < 100), room TEXT)",
+            )?;
+            anyhow::Ok(serde_yaml::from_str::<ConfigDatabase>(&format!(
+                "{{ type: sqlite, path: '{}', table: {{ name: readings }}, {} }}",
+                path, extra
+            ))?)
+        };
+
+        // Only the point the database refuses is dropped from a batch.
+        let db_path = temp_path("rejected.db");
+        let config = mk_config(&db_path, "batch: { maxSize: 5, maxAge: { secs: 60, nanos: 0 } }")?;
+        let database = init_db(&config, &["room".to_string()], &[]).await?;
+        let writes = [1.0, 2.0, 300.0, 4.0, 5.0]
+            .iter()
+            .enumerate()
+            .map(|(i, value)| database.write(mk_point(i as u128, *value)));
+        let results = tokio::time::timeout(Duration::from_secs(5), join_all(writes)).await?;
+        for (i, result) in results.iter().enumerate() {
+            assert_eq!(i == 2, matches!(result, Err(WriteError::Permanent(_))), "point {}: {:?}", i, result);
+        }
+        assert_eq!(4, count_rows(&db_path)?);
+
+        // Likewise from the spool.
+        let db_path = temp_path("rejected-replay.db");
+        let spool_config = SpoolConfig {
+            path: temp_path("rejected-replay.spool"),
+            max_size: 1024 * 1024,
+            drop_policy: DropPolicy::Oldest,
+        };
+        Spool::open(&spool_config)
+            .await?
+            .append(&[mk_point(1, 1.0), mk_point(2, 200.0), mk_point(3, 3.0)])
+            .await?;
+        let config = mk_config(&db_path, &format!("spool: {{ path: '{}' }}", spool_config.path))?;
+        let _database = init_db(&config, &["room".to_string()], &[]).await?;
+        for _ in 0..50 {
+            if Spool::open(&spool_config).await?.is_empty() {
+                break;
+            }
+            sleep(Duration::from_millis(20)).await;
+        }
+        assert!(Spool::open(&spool_config).await?.is_empty());
+        assert_eq!(2, count_rows(&db_path)?);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn spool_replay_on_startup() -> anyhow::Result<()> {
+        let db_path = temp_path("replay.db");
+        let spool_config = SpoolConfig {
+            path: temp_path("replay.spool"),
+            max_size: 1024 * 1024,
+            drop_policy: DropPolicy::Oldest,
+        };
+        Spool::open(&spool_config)
+            .await?
+            .append(&[mk_point(1, 1.0), mk_point(2, 2.0)])
+            .await?;
+
+        let config = serde_yaml::from_str::<ConfigDatabase>(&format!(
+            "{{ type: sqlite, path: '{}', table: {{ name: readings, create: true }}, spool: {{ path: '{}' }} }}",
+            db_path, spool_config.path
+        ))?;
+        let _database = init_db(&config, &["room".to_string()], &[]).await?;
+
+        // No points are written, but the spool should be replayed anyway.
+        for _ in 0..50 {
+            if count_rows(&db_path)? == 2 {
+                break;
+            }
+            sleep(Duration::from_millis(20)).await;
+        }
+        assert_eq!(2, count_rows(&db_path)?);
+        assert!(Spool::open(&spool_config).await?.is_empty());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn spool_handover() -> anyhow::Result<()> {
+        let mk_config = |name: &str, spool_path: &str| {
+            serde_yaml::from_str::<ConfigDatabase>(&format!(
+                "{{ type: sqlite, path: '{}', table: {{ name: readings, create: true }}, spool: {{ path: '{}' }} }}",
+                temp_path(name),
+                spool_path
+            ))
+        };
+        let spool_path = temp_path("handover.spool");
+        let shared_spool = |database: &Database| database.spool.as_ref().map(|(_, spool)| Arc::clone(spool));
+
+        let old_database = init_db(&mk_config("handover-old.db", &spool_path)?, &[], &[]).await?;
+        let previous = [old_database];
+        let new_database = init_db(&mk_config("handover-new.db", &spool_path)?, &[], &previous).await?;
+        assert!(Arc::ptr_eq(&shared_spool(&previous[0]).unwrap(), &shared_spool(&new_database).unwrap()));
+
+        let other_database = init_db(&mk_config("handover-other.db", &temp_path("other.spool"))?, &[], &previous).await?;
+        assert!(!Arc::ptr_eq(&shared_spool(&previous[0]).unwrap(), &shared_spool(&other_database).unwrap()));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,270 @@
+// mqtt2db -- subscries to MQTT topics and writes to a database
+// Copyright (C) 2021-2022 Brian Tarricone <brian@tarricone.org>
+// 
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+// 
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+// 
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::error::Error;
+use tokio::sync::Mutex;
+use tokio_postgres::types::ToSql;
+use tokio_postgres::error::SqlState;
+use tokio_postgres::{Client, Config as PgConfig, Error as PgError, NoTls, Statement};
+
+use super::{quote_identifier, Point, SqlSchema, SqlValue, WriteError};
+use crate::config::SqlTable;
+
+struct Connection {
+    client: Client,
+    insert_statement: Statement,
+}
+
+/// Connection problems, and errors in the SQLSTATE classes for connection
+/// exceptions (08), transaction rollbacks (40), insufficient resources (53)
+/// and operator intervention (57), are worth retrying; anything else means
+/// the write itself is bad.
+fn classify_error(context: &str, err: PgError) -> WriteError {
+    let transient = err.is_closed()
+        || match err.code() {
+            Some(code) => {
+                ["08", "40", "53", "57"].iter().any(|class| code.code().starts_with(class))
+                    || *code == SqlState::LOCK_NOT_AVAILABLE
+            }
+            None => err
+                .source()
+                .map(|source| source.is::<std::io::Error>())
+                .unwrap_or(false),
+        };
+    let err = anyhow!("{}: {}", context, err);
+    if transient {
+        WriteError::Transient(err)
+    } else {
+        WriteError::Permanent(err)
+    }
+}
+
+pub struct PostgresClient {
+    config: PgConfig,
+    schema: SqlSchema,
+    hypertable_sql: Option<String>,
+    insert_sql: String,
+    connection: Mutex<Option<Connection>>,
+}
+
+impl PostgresClient {
+    pub fn new(connection: &str, table: &SqlTable, tag_names: &[String]) -> anyhow::Result<PostgresClient> {
+        let config = connection
+            .parse::<PgConfig>()
+            .map_err(|err| anyhow!("Invalid PostgreSQL connection string: {}", err))?;
+
+        let schema = SqlSchema::new(table, tag_names);
+        let hypertable_sql = table.hypertable.then(|| {
+            format!(
+                "SELECT create_hypertable('{}', '{}', if_not_exists => TRUE)",
+                quote_identifier(&table.name).replace('\'', "''"),
+                table.time_column.replace('\'', "''")
+            )
+        });
+        let insert_sql = schema.insert_sql('$');
+
+        Ok(PostgresClient {
+            config,
+            schema,
+            hypertable_sql,
+            insert_sql,
+            connection: Mutex::new(None),
+        })
+    }
+
+    async fn open(&self) -> Result<Connection, WriteError> {
+        let (client, connection) = self
+            .config
+            .connect(NoTls)
+            .await
+            .map_err(|err| classify_error("Failed to connect to PostgreSQL", err))?;
+        tokio::spawn(async move {
+            if let Err(err) = connection.await {
+                warn!("PostgreSQL connection error: {}", err);
+            }
+        });
+
+        let existing_columns = match self.schema.create_sql("TIMESTAMPTZ") {
+            Some(create_sql) => {
+                self.set_up(&client, &create_sql).await?;
+                self.table_columns(&client).await?
+            }
+            None => self.table_columns(&client).await?,
+        };
+        let alter_sql = self
+            .schema
+            .alter_sql(&existing_columns)
+            .map_err(|err| WriteError::Permanent(anyhow!("Failed to set up PostgreSQL table: {}", err)))?;
+        for sql in alter_sql.iter().chain(self.hypertable_sql.iter()) {
+            self.set_up(&client, sql).await?;
+        }
+
+        let insert_statement = client
+            .prepare(&self.insert_sql)
+            .await
+            .map_err(|err| classify_error("Failed to prepare PostgreSQL insert", err))?;
+
+        Ok(Connection {
+            client,
+            insert_statement,
+        })
+    }
+
+    async fn set_up(&self, client: &Client, sql: &str) -> Result<(), WriteError> {
+        debug!("Running table setup: {}", sql);
+        client
+            .batch_execute(sql)
+            .await
+            .map_err(|err| classify_error("Failed to set up PostgreSQL table", err))
+    }
+
+    async fn table_columns(&self, client: &Client) -> Result<Vec<String>, WriteError> {
+        let rows = client
+            .query(
+                "SELECT column_name FROM information_schema.columns \
+                 WHERE table_name = $1 AND table_schema = ANY(current_schemas(false))",
+                &[&self.schema.table_name],
+            )
+            .await
+            .map_err(|err| classify_error("Failed to look up PostgreSQL table columns", err))?;
+        Ok(rows.iter().map(|row| row.get(0)).collect())
+    }
+
+    pub async fn connect(&self) -> Result<(), WriteError> {
+        let mut connection = self.connection.lock().await;
+        *connection = Some(self.open().await?);
+        Ok(())
+    }
+
+    pub async fn write(&self, points: &[Point]) -> Result<(), WriteError> {
+        let rows = self.schema.rows(points);
+
+        let mut connection = self.connection.lock().await;
+        if connection.as_ref().map(|conn| conn.client.is_closed()).unwrap_or(true) {
+            *connection = Some(self.open().await?);
+        }
+        let conn = connection.as_mut().unwrap();
+
+        let transaction = conn
+            .client
+            .transaction()
+            .await
+            .map_err(|err| classify_error("Failed to start PostgreSQL transaction", err))?;
+        for row in rows.iter() {
+            let value: &(dyn ToSql + Sync) = match &row.value {
+                SqlValue::Boolean(b) => b,
+                SqlValue::Float(f) => f,
+                SqlValue::Integer(i) => i,
+                SqlValue::Text(s) => s,
+            };
+            let mut params: Vec<&(dyn ToSql + Sync)> = vec![&row.time, &row.field_name, value];
+            params.extend(row.tag_values.iter().map(|tag_value| tag_value as &(dyn ToSql + Sync)));
+            transaction
+                .execute(&conn.insert_statement, &params)
+                .await
+                .map_err(|err| classify_error("Failed to insert into PostgreSQL", err))?;
+        }
+        transaction
+            .commit()
+            .await
+            .map_err(|err| classify_error("Failed to commit PostgreSQL transaction", err))?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_util::mk_point;
+    use chrono::{DateTime, TimeZone, Utc};
+
+    #[test]
+    fn postgres_statements() -> anyhow::Result<()> {
+        let table = serde_yaml::from_str::<SqlTable>("{ name: \"read'ings\", timeColumn: ts, hypertable: true }")?;
+        let client = PostgresClient::new("host=localhost", &table, &["room".to_string()])?;
+        assert_eq!(
+            client.insert_sql,
+            "INSERT INTO \"read'ings\" (\"ts\", \"field\", \"value\", \"room\") VALUES ($1, $2, $3, $4)"
+        );
+        assert_eq!(
+            client.hypertable_sql.as_deref(),
+            Some("SELECT create_hypertable('\"read''ings\"', 'ts', if_not_exists => TRUE)")
+        );
+
+        let table = serde_yaml::from_str::<SqlTable>("{ name: readings, create: true }")?;
+        let client = PostgresClient::new("host=localhost", &table, &["room".to_string()])?;
+        assert_eq!(client.hypertable_sql, None);
+        assert_eq!(
+            client.schema.create_sql("TIMESTAMPTZ").as_deref(),
+            Some(
+                "CREATE TABLE IF NOT EXISTS \"readings\" (\"time\" TIMESTAMPTZ NOT NULL, \"field\" TEXT NOT NULL, \
+                 \"value\" DOUBLE PRECISION, \"room\" TEXT)"
+            )
+        );
+        assert_eq!(
+            client.schema.alter_sql(&["time", "field", "value"].map(String::from))?,
+            vec!["ALTER TABLE \"readings\" ADD COLUMN \"room\" TEXT".to_string()]
+        );
+
+        assert!(PostgresClient::new("host=localhost port=nope", &table, &[]).is_err());
+
+        Ok(())
+    }
+
+    /// Needs a PostgreSQL server; point `MQTT2DB_TEST_POSTGRES` at it and
+    /// run with `cargo test -- --ignored`.
+    #[tokio::test]
+    #[ignore]
+    async fn postgres_round_trip() -> anyhow::Result<()> {
+        let connection =
+            std::env::var("MQTT2DB_TEST_POSTGRES").unwrap_or_else(|_| "host=localhost user=postgres".to_string());
+        let table_name = format!("mqtt2db_test_{}", std::process::id());
+        let (client, conn) = tokio_postgres::connect(&connection, NoTls).await?;
+        tokio::spawn(conn);
+
+        // A table from before the room tag was added.
+        client
+            .batch_execute(&format!(
+                "CREATE TABLE {} (time TIMESTAMPTZ NOT NULL, field TEXT NOT NULL, value DOUBLE PRECISION)",
+                table_name
+            ))
+            .await?;
+        let table = serde_yaml::from_str::<SqlTable>(&format!("{{ name: {}, create: true }}", table_name))?;
+        let result = async {
+            let postgres = PostgresClient::new(&connection, &table, &["room".to_string()])?;
+            postgres.write(&[mk_point(1_000, 1.5), mk_point(2_000, 2.5)]).await?;
+            let rows = client
+                .query(&format!("SELECT time, field, value, room FROM {} ORDER BY time", table_name), &[])
+                .await?
+                .iter()
+                .map(|row| (row.get(0), row.get(1), row.get(2), row.get(3)))
+                .collect::<Vec<(DateTime<Utc>, String, f64, String)>>();
+            assert_eq!(
+                vec![
+                    (Utc.timestamp_nanos(1_000), "value".to_string(), 1.5, "kitchen".to_string()),
+                    (Utc.timestamp_nanos(2_000), "value".to_string(), 2.5, "kitchen".to_string()),
+                ],
+                rows
+            );
+            anyhow::Ok(())
+        }
+        .await;
+
+        client.batch_execute(&format!("DROP TABLE {}", table_name)).await?;
+        result
+    }
+}
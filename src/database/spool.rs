@@ -0,0 +1,294 @@
+// mqtt2db -- subscries to MQTT topics and writes to a database
+// Copyright (C) 2021-2022 Brian Tarricone <brian@tarricone.org>
+// 
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+// 
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+// 
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use influxdb::Type;
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use tokio::fs::{self, OpenOptions};
+use tokio::io::AsyncWriteExt;
+
+use super::Point;
+use crate::config::{DropPolicy, SpoolConfig};
+
+#[derive(Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case", tag = "type", content = "value")]
+enum SpooledValue {
+    Boolean(bool),
+    Float(f64),
+    SignedInteger(i64),
+    UnsignedInteger(u64),
+    Text(String),
+}
+
+impl From<&Type> for SpooledValue {
+    fn from(value: &Type) -> Self {
+        match value {
+            Type::Boolean(b) => SpooledValue::Boolean(*b),
+            Type::Float(f) => SpooledValue::Float(*f),
+            Type::SignedInteger(i) => SpooledValue::SignedInteger(*i),
+            Type::UnsignedInteger(u) => SpooledValue::UnsignedInteger(*u),
+            Type::Text(s) => SpooledValue::Text(s.clone()),
+        }
+    }
+}
+
+impl From<SpooledValue> for Type {
+    fn from(value: SpooledValue) -> Self {
+        match value {
+            SpooledValue::Boolean(b) => Type::Boolean(b),
+            SpooledValue::Float(f) => Type::Float(f),
+            SpooledValue::SignedInteger(i) => Type::SignedInteger(i),
+            SpooledValue::UnsignedInteger(u) => Type::UnsignedInteger(u),
+            SpooledValue::Text(s) => Type::Text(s),
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize)]
+struct SpooledPoint {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    measurement: Option<String>,
+    timestamp: u128,
+    fields: Vec<(String, SpooledValue)>,
+    tags: Vec<(String, SpooledValue)>,
+}
+
+impl From<&Point> for SpooledPoint {
+    fn from(point: &Point) -> Self {
+        SpooledPoint {
+            measurement: point.measurement.clone(),
+            timestamp: point.timestamp,
+            fields: point.fields.iter().map(|(name, value)| (name.clone(), value.into())).collect(),
+            tags: point.tags.iter().map(|(name, value)| (name.clone(), value.into())).collect(),
+        }
+    }
+}
+
+impl From<SpooledPoint> for Point {
+    fn from(point: SpooledPoint) -> Self {
+        Point {
+            measurement: point.measurement,
+            timestamp: point.timestamp,
+            fields: point.fields.into_iter().map(|(name, value)| (name, value.into())).collect(),
+            tags: point.tags.into_iter().map(|(name, value)| (name, value.into())).collect(),
+        }
+    }
+}
+
+/// An append-only file of points that could not be written, stored as one
+/// JSON object per line, oldest first.
+pub struct Spool {
+    path: PathBuf,
+    max_size: u64,
+    drop_policy: DropPolicy,
+    size: u64,
+}
+
+impl Spool {
+    pub async fn open(config: &SpoolConfig) -> anyhow::Result<Spool> {
+        let path = PathBuf::from(&config.path);
+        let size = match fs::metadata(&path).await {
+            Ok(metadata) => metadata.len(),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => 0,
+            Err(err) => Err(anyhow!("Unable to open spool file '{}': {}", config.path, err))?,
+        };
+        if size > 0 {
+            info!("Spool file '{}' has {} bytes of points to replay", config.path, size);
+        }
+
+        Ok(Spool {
+            path,
+            max_size: config.max_size,
+            drop_policy: config.drop_policy,
+            size,
+        })
+    }
+
+    /// Takes on new limits, when a reloaded config keeps the same spool file.
+    pub fn configure(&mut self, config: &SpoolConfig) {
+        self.max_size = config.max_size;
+        self.drop_policy = config.drop_policy;
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.size == 0
+    }
+
+    pub async fn append(&mut self, points: &[Point]) -> anyhow::Result<()> {
+        let new_lines = points
+            .iter()
+            .map(|point| serde_json::to_string(&SpooledPoint::from(point)).map(|line| line + "\n"))
+            .collect::<Result<Vec<String>, _>>()?;
+        let new_size = new_lines.iter().map(|line| line.len() as u64).sum::<u64>();
+
+        if self.size + new_size <= self.max_size {
+            let mut file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&self.path)
+                .await?;
+            file.write_all(new_lines.concat().as_bytes()).await?;
+            file.sync_data().await?;
+            self.size += new_size;
+            return Ok(());
+        }
+
+        // When it's the points being written that get dropped, the caller
+        // has to know, so they aren't taken as stored.
+        if matches!(self.drop_policy, DropPolicy::Newest) || new_size > self.max_size {
+            Err(anyhow!(
+                "Spool file '{}' is full; dropped {} new points",
+                self.path.display(),
+                points.len()
+            ))?;
+        }
+
+        let mut lines = self.read_lines().await?;
+        lines.extend(new_lines);
+        let mut size = lines.iter().map(|line| line.len() as u64).sum::<u64>();
+        let mut n_dropped = 0;
+        while size > self.max_size && n_dropped < lines.len() {
+            size -= lines[n_dropped].len() as u64;
+            n_dropped += 1;
+        }
+        lines.drain(..n_dropped);
+        warn!(
+            "Spool file '{}' is full; dropped {} points",
+            self.path.display(),
+            n_dropped
+        );
+
+        self.write_lines(&lines).await
+    }
+
+    pub async fn read(&self) -> anyhow::Result<Vec<Point>> {
+        Ok(self
+            .read_lines()
+            .await?
+            .iter()
+            .filter_map(|line| match serde_json::from_str::<SpooledPoint>(line) {
+                Ok(point) => Some(Point::from(point)),
+                Err(err) => {
+                    warn!("Skipping corrupt line in spool file '{}': {}", self.path.display(), err);
+                    None
+                }
+            })
+            .collect())
+    }
+
+    pub async fn replace(&mut self, points: &[Point]) -> anyhow::Result<()> {
+        let lines = points
+            .iter()
+            .map(|point| serde_json::to_string(&SpooledPoint::from(point)).map(|line| line + "\n"))
+            .collect::<Result<Vec<String>, _>>()?;
+        self.write_lines(&lines).await
+    }
+
+    async fn read_lines(&self) -> anyhow::Result<Vec<String>> {
+        match fs::read_to_string(&self.path).await {
+            Ok(contents) => Ok(contents.lines().map(|line| line.to_string() + "\n").collect()),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(Vec::new()),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    async fn write_lines(&mut self, lines: &[String]) -> anyhow::Result<()> {
+        if lines.is_empty() {
+            match fs::remove_file(&self.path).await {
+                Ok(_) => (),
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => (),
+                Err(err) => Err(err)?,
+            }
+            self.size = 0;
+            return Ok(());
+        }
+
+        // Write to a temporary file and rename it over the spool, so a power
+        // cut can't leave a half-written spool behind.
+        let contents = lines.concat();
+        let tmp_path = self.path.with_extension("tmp");
+        let mut file = fs::File::create(&tmp_path).await?;
+        file.write_all(contents.as_bytes()).await?;
+        file.sync_all().await?;
+        fs::rename(&tmp_path, &self.path).await?;
+        self.size = contents.len() as u64;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_util::{mk_point, temp_path};
+
+    async fn mk_spool(name: &str, max_size: u64, drop_policy: DropPolicy) -> anyhow::Result<Spool> {
+        Spool::open(&SpoolConfig {
+            path: temp_path(&format!("{}.spool", name)),
+            max_size,
+            drop_policy,
+        })
+        .await
+    }
+
+    fn timestamps(points: &[Point]) -> Vec<u128> {
+        points.iter().map(|point| point.timestamp).collect()
+    }
+
+    #[tokio::test]
+    async fn spool_round_trip() -> anyhow::Result<()> {
+        let mut spool = mk_spool("round-trip", 1024 * 1024, DropPolicy::Oldest).await?;
+        assert!(spool.is_empty());
+
+        spool.append(&[mk_point(1, 1.0), mk_point(2, 2.0)]).await?;
+        spool.append(&[mk_point(3, 3.0)]).await?;
+        assert!(!spool.is_empty());
+
+        let points = spool.read().await?;
+        assert_eq!(vec![1, 2, 3], timestamps(&points));
+        assert!(matches!(&points[0].tags[0].1, Type::Text(room) if room == "kitchen"));
+
+        spool.replace(&points[1..]).await?;
+        assert_eq!(vec![2, 3], timestamps(&spool.read().await?));
+
+        spool.replace(&[]).await?;
+        assert!(spool.is_empty());
+        assert!(spool.read().await?.is_empty());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn spool_drop_policy() -> anyhow::Result<()> {
+        let line_size = serde_json::to_string(&SpooledPoint::from(&mk_point(1, 1.0)))?.len() as u64 + 1;
+
+        let mut spool = mk_spool("drop-oldest", line_size * 2, DropPolicy::Oldest).await?;
+        spool.append(&[mk_point(1, 1.0), mk_point(2, 2.0)]).await?;
+        spool.append(&[mk_point(3, 3.0)]).await?;
+        assert_eq!(vec![2, 3], timestamps(&spool.read().await?));
+        // Points that can't fit even in an empty spool are refused outright.
+        assert!(spool.append(&[mk_point(4, 4.0), mk_point(5, 5.0), mk_point(6, 6.0)]).await.is_err());
+        assert_eq!(vec![2, 3], timestamps(&spool.read().await?));
+        spool.replace(&[]).await?;
+
+        let mut spool = mk_spool("drop-newest", line_size * 2, DropPolicy::Newest).await?;
+        spool.append(&[mk_point(1, 1.0), mk_point(2, 2.0)]).await?;
+        assert!(spool.append(&[mk_point(3, 3.0)]).await.is_err());
+        assert_eq!(vec![1, 2], timestamps(&spool.read().await?));
+        spool.replace(&[]).await?;
+
+        Ok(())
+    }
+}
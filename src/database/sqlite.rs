@@ -0,0 +1,210 @@
+// mqtt2db -- subscries to MQTT topics and writes to a database
+// Copyright (C) 2021-2022 Brian Tarricone <brian@tarricone.org>
+// 
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+// 
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+// 
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use chrono::SecondsFormat;
+use rusqlite::types::Value as SqliteValue;
+use rusqlite::{params_from_iter, Connection, ErrorCode};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::thread;
+use tokio::sync::oneshot;
+
+use super::{Point, SqlSchema, SqlValue, WriteError};
+use crate::config::SqlTable;
+
+type Row = Vec<SqliteValue>;
+
+struct WriteRequest {
+    rows: Vec<Row>,
+    result_sender: oneshot::Sender<Result<(), WriteError>>,
+}
+
+pub struct SqliteClient {
+    schema: SqlSchema,
+    request_sender: Sender<WriteRequest>,
+}
+
+impl SqliteClient {
+    pub fn new(path: &str, table: &SqlTable, wal: bool, tag_names: &[String]) -> anyhow::Result<SqliteClient> {
+        let conn = Connection::open(path)
+            .map_err(|err| anyhow!("Failed to open SQLite database '{}': {}", path, err))?;
+        if wal {
+            conn.pragma_update(None, "journal_mode", "WAL")
+                .and_then(|_| conn.pragma_update(None, "synchronous", "NORMAL"))
+                .map_err(|err| anyhow!("Failed to enable WAL mode for '{}': {}", path, err))?;
+        }
+
+        let schema = SqlSchema::new(table, tag_names);
+        let set_up = |sql: &str| {
+            debug!("Running table setup: {}", sql);
+            conn.execute_batch(sql)
+                .map_err(|err| anyhow!("Failed to set up SQLite table: {}", err))
+        };
+        if let Some(create_sql) = schema.create_sql("TEXT") {
+            set_up(&create_sql)?;
+        }
+        let existing_columns = table_columns(&conn, &table.name)
+            .map_err(|err| anyhow!("Failed to look up SQLite table columns: {}", err))?;
+        for sql in schema
+            .alter_sql(&existing_columns)
+            .map_err(|err| anyhow!("Failed to set up SQLite table: {}", err))?
+        {
+            set_up(&sql)?;
+        }
+
+        let insert_sql = schema.insert_sql('?');
+        conn.prepare_cached(&insert_sql)
+            .map_err(|err| anyhow!("Failed to prepare SQLite insert: {}", err))?;
+
+        let (request_sender, request_receiver) = mpsc::channel();
+        thread::Builder::new()
+            .name("sqlite-writer".to_string())
+            .spawn(move || run_writer(conn, insert_sql, request_receiver))?;
+
+        Ok(SqliteClient {
+            schema,
+            request_sender,
+        })
+    }
+
+    pub async fn write(&self, points: &[Point]) -> Result<(), WriteError> {
+        let rows = self
+            .schema
+            .rows(points)
+            .into_iter()
+            .map(|row| {
+                let value = match row.value {
+                    SqlValue::Boolean(b) => SqliteValue::Integer(b as i64),
+                    SqlValue::Float(f) => SqliteValue::Real(f),
+                    SqlValue::Integer(i) => SqliteValue::Integer(i),
+                    SqlValue::Text(s) => SqliteValue::Text(s),
+                };
+                let time = row.time.to_rfc3339_opts(SecondsFormat::Nanos, true);
+                let mut values = vec![SqliteValue::Text(time), SqliteValue::Text(row.field_name), value];
+                values.extend(
+                    row.tag_values
+                        .into_iter()
+                        .map(|tag_value| tag_value.map_or(SqliteValue::Null, SqliteValue::Text)),
+                );
+                values
+            })
+            .collect::<Vec<Row>>();
+
+        let (result_sender, result_receiver) = oneshot::channel();
+        self.request_sender
+            .send(WriteRequest { rows, result_sender })
+            .map_err(|_| WriteError::Permanent(anyhow!("SQLite writer has stopped")))?;
+        result_receiver
+            .await
+            .map_err(|_| WriteError::Permanent(anyhow!("SQLite writer has stopped")))?
+    }
+}
+
+fn table_columns(conn: &Connection, table_name: &str) -> rusqlite::Result<Vec<String>> {
+    let mut statement = conn.prepare("SELECT name FROM pragma_table_info(?1)")?;
+    let columns = statement.query_map([table_name], |row| row.get(0))?;
+    columns.collect()
+}
+
+fn run_writer(mut conn: Connection, insert_sql: String, request_receiver: Receiver<WriteRequest>) {
+    // Each request is a whole batch of points, which gets committed in a
+    // single transaction.
+    while let Ok(request) = request_receiver.recv() {
+        let result = write_rows(&mut conn, &insert_sql, &request.rows).map_err(classify_error);
+        let _ = request.result_sender.send(result);
+    }
+}
+
+/// A busy or locked database (another process holding a write lock), or a
+/// full disk, may clear up on its own; anything else won't.
+fn classify_error(err: rusqlite::Error) -> WriteError {
+    let transient = matches!(
+        err.sqlite_error_code(),
+        Some(ErrorCode::DatabaseBusy) | Some(ErrorCode::DatabaseLocked) | Some(ErrorCode::DiskFull)
+    );
+    let err = anyhow!("Failed to write to SQLite: {}", err);
+    if transient {
+        WriteError::Transient(err)
+    } else {
+        WriteError::Permanent(err)
+    }
+}
+
+fn write_rows(conn: &mut Connection, insert_sql: &str, rows: &[Row]) -> rusqlite::Result<()> {
+    let transaction = conn.transaction()?;
+    {
+        let mut statement = transaction.prepare_cached(insert_sql)?;
+        for row in rows.iter() {
+            statement.execute(params_from_iter(row.iter()))?;
+        }
+    }
+    transaction.commit()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_util::{mk_point, temp_path};
+
+    #[tokio::test]
+    async fn sqlite_round_trip() -> anyhow::Result<()> {
+        let path = temp_path("round-trip.db");
+        let table = serde_yaml::from_str::<SqlTable>("{ name: readings, create: true }")?;
+        let client = SqliteClient::new(&path, &table, true, &["room".to_string()])?;
+        client.write(&[mk_point(1, 1.5), mk_point(2, 2.5)]).await?;
+
+        let conn = Connection::open(&path)?;
+        let journal_mode = conn.query_row("PRAGMA journal_mode", [], |row| row.get::<_, String>(0))?;
+        assert_eq!("wal", journal_mode);
+
+        let rows = conn
+            .prepare("SELECT time, field, value, room FROM readings ORDER BY time")?
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)))?
+            .collect::<rusqlite::Result<Vec<(String, String, f64, String)>>>()?;
+        assert_eq!(
+            vec![
+                ("1970-01-01T00:00:00.000000001Z".to_string(), "value".to_string(), 1.5, "kitchen".to_string()),
+                ("1970-01-01T00:00:00.000000002Z".to_string(), "value".to_string(), 2.5, "kitchen".to_string()),
+            ],
+            rows
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn sqlite_batch_is_one_transaction() -> anyhow::Result<()> {
+        let path = temp_path("transaction.db");
+        Connection::open(&path)?.execute_batch(
+            "CREATE TABLE readings (time TEXT NOT NULL, field TEXT NOT NULL, value REAL CHECK (value < 100))",
+        )?;
+        let table = serde_yaml::from_str::<SqlTable>("{ name: readings }")?;
+        let client = SqliteClient::new(&path, &table, false, &[])?;
+
+        // The second point violates the constraint, so the first one has to
+        // be rolled back along with it.
+        let result = client.write(&[mk_point(1, 1.0), mk_point(2, 200.0)]).await;
+        assert!(matches!(result, Err(WriteError::Permanent(_))));
+        let count_rows = || {
+            Connection::open(&path)?.query_row("SELECT COUNT(*) FROM readings", [], |row| row.get::<_, i64>(0))
+        };
+        assert_eq!(0, count_rows()?);
+
+        client.write(&[mk_point(1, 1.0), mk_point(2, 2.0)]).await?;
+        assert_eq!(2, count_rows()?);
+
+        Ok(())
+    }
+}
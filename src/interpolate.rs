@@ -19,7 +19,52 @@ use regex::Regex;
 use std::convert::TryFrom;
 
 lazy_static! {
-    static ref REFERENCE_RE: Regex = Regex::new(r"(^|[^\\])(\$(\d+))").unwrap();
+    static ref REFERENCE_RE: Regex = Regex::new(r"(^|[^\\])(\$(\d+)|\$\(([^)]*)\))").unwrap();
+}
+
+/// A named value from the message itself, referenced as `$(name)`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Variable {
+    /// The name of the broker the message came from.
+    Broker,
+    ContentType,
+    UserProperty(String),
+}
+
+impl TryFrom<&str> for Variable {
+    type Error = anyhow::Error;
+    fn try_from(s: &str) -> Result<Self, Self::Error> {
+        match s.split_once(':') {
+            None if s == "broker" => Ok(Variable::Broker),
+            None if s == "contentType" => Ok(Variable::ContentType),
+            Some(("userProperty", key)) if !key.is_empty() => Ok(Variable::UserProperty(key.to_string())),
+            _ => Err(anyhow!("Unknown variable '$({})'", s)),
+        }
+    }
+}
+
+/// Values for the variables available when interpolating a name; apart
+/// from the broker, these come from MQTT 5 message properties, so are
+/// often absent.
+#[derive(Default)]
+pub struct Variables<'a> {
+    pub broker: Option<&'a str>,
+    pub content_type: Option<&'a str>,
+    pub user_properties: &'a [(String, String)],
+}
+
+impl Variables<'_> {
+    fn get(&self, variable: &Variable) -> Option<&str> {
+        match variable {
+            Variable::Broker => self.broker,
+            Variable::ContentType => self.content_type,
+            Variable::UserProperty(key) => self
+                .user_properties
+                .iter()
+                .find(|(name, _)| name == key)
+                .map(|(_, value)| value.as_str()),
+        }
+    }
 }
 
 #[derive(Clone, Debug, PartialEq)]
@@ -32,6 +77,7 @@ pub struct InterpolatedName {
 pub enum InterpolatedNamePart {
     Literal(String),
     Reference(usize),
+    Variable(Variable),
 }
 
 impl TryFrom<&str> for InterpolatedName {
@@ -55,6 +101,12 @@ impl TryFrom<&str> for InterpolatedName {
                 ));
             }
 
+            if let Some(variable) = cap.get(4) {
+                parts.push(InterpolatedNamePart::Variable(Variable::try_from(variable.as_str())?));
+                pos = mat.end();
+                continue;
+            }
+
             let num_str = cap
                 .get(3)
                 .map(|mat1| mat1.as_str())
@@ -85,27 +137,31 @@ impl TryFrom<&str> for InterpolatedName {
 }
 
 impl InterpolatedName {
-    pub fn interpolate<S: AsRef<str>>(&self, reference_values: &Vec<S>) -> anyhow::Result<String> {
+    pub fn interpolate<S: AsRef<str>>(&self, reference_values: &[S], variables: &Variables) -> anyhow::Result<String> {
         self.parts
             .iter()
-            .fold(Ok(String::new()), |accum, part| match accum {
-                Ok(mut accum) => match part {
-                    InterpolatedNamePart::Literal(s) => {
-                        accum.push_str(s.as_str());
+            .try_fold(String::new(), |mut accum, part| match part {
+                InterpolatedNamePart::Literal(s) => {
+                    accum.push_str(s.as_str());
+                    Ok(accum)
+                }
+                InterpolatedNamePart::Reference(num) => match reference_values.get(*num - 1) {
+                    Some(reference_value) => {
+                        accum.push_str(reference_value.as_ref());
+                        Ok(accum)
+                    }
+                    None => Err(anyhow!(
+                        "Can't find reference number {} to interpolate",
+                        num
+                    )),
+                },
+                InterpolatedNamePart::Variable(variable) => match variables.get(variable) {
+                    Some(value) => {
+                        accum.push_str(value);
                         Ok(accum)
                     }
-                    InterpolatedNamePart::Reference(num) => match reference_values.get(*num - 1) {
-                        Some(reference_value) => {
-                            accum.push_str(reference_value.as_ref());
-                            Ok(accum)
-                        }
-                        None => Err(anyhow!(
-                            "Can't find reference number {} to interpolate",
-                            num
-                        )),
-                    },
+                    None => Err(anyhow!("Message has no value for variable {:?} to interpolate", variable)),
                 },
-                Err(err) => Err(err),
             })
     }
 }
@@ -147,6 +203,20 @@ mod test {
 
         assert!(InterpolatedName::try_from("$0").is_err());
 
+        assert_eq!(
+            vec![
+                Literal("foo_".to_string()),
+                Variable(super::Variable::ContentType),
+                Literal("_".to_string()),
+                Variable(super::Variable::UserProperty("site".to_string())),
+                Literal("$(x)".to_string())
+            ],
+            InterpolatedName::try_from("foo_$(contentType)_$(userProperty:site)\\$(x)")?.parts
+        );
+
+        assert!(InterpolatedName::try_from("$(bogus)").is_err());
+        assert!(InterpolatedName::try_from("$(userProperty:)").is_err());
+
         Ok(())
     }
 
@@ -156,12 +226,22 @@ mod test {
         assert_eq!(
             "foofirstbarsecond baz first".to_string(),
             interp
-                .interpolate(&vec!["first".to_string(), "second".to_string()])
+                .interpolate(&["first".to_string(), "second".to_string()], &Variables::default())
                 .unwrap()
         );
 
         let empty: Vec<String> = vec![];
-        assert!(interp.interpolate(&empty).is_err());
+        assert!(interp.interpolate(&empty, &Variables::default()).is_err());
+
+        let interp = InterpolatedName::try_from("$(contentType) $(userProperty:site) $1 $(broker)")?;
+        let user_properties = vec![("site".to_string(), "berlin".to_string())];
+        let variables = Variables {
+            broker: Some("plant-2"),
+            content_type: Some("text/plain"),
+            user_properties: &user_properties,
+        };
+        assert_eq!("text/plain berlin x plant-2", interp.interpolate(&["x"], &variables)?);
+        assert!(interp.interpolate(&["x"], &Variables::default()).is_err());
 
         Ok(())
     }
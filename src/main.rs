@@ -19,105 +19,215 @@ extern crate anyhow;
 #[macro_use]
 extern crate log;
 
-use config::{Config, Database as ConfigDatabase, MqttAuth, MqttConfig, UserAuth};
-use influxdb::InfluxDbWriteable;
-use influxdb::{Client as InfluxClient, Timestamp, Type};
-use mapping::{Mapping, Payload, TagValue, TopicLevel};
-use rumqttc::{
-    AsyncClient as MqttAsyncClient, Event, EventLoop as MqttEventLoop, Key, MqttOptions, Packet,
-    Publish, QoS, SubscribeFilter, TlsConfiguration, Transport,
-};
+use cli::{Cli, Command};
+use backoff::backoff_delay;
+use config::{Config, MatchMode, MqttConfig};
+use database::{init_db, Database, Point, WriteError};
+use futures::future::join_all;
+use influxdb::Type;
+use interpolate::Variables;
+use mapping::{carry_over_retained, Mapping, Payload, TagValue, TopicLevel};
+use mqtt::{init_mqtt, Message, MqttClient, MqttEvent, MqttEventLoop};
+use rumqttc::QoS;
 use serde_json::Value as JsonValue;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::convert::TryFrom;
-use std::env;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use std::time::{SystemTime, UNIX_EPOCH};
-use tokio::fs;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::{oneshot, watch};
+use tokio::time::sleep;
 use value::ToInfluxType;
 
+mod backoff;
+mod cli;
 mod config;
+mod database;
 mod interpolate;
 mod mapping;
+mod mqtt;
+#[cfg(test)]
+mod test_util;
 mod value;
 
-struct Database {
-    client: InfluxClient,
-    measurement: String,
+async fn init_subscriptions(
+    broker_name: &str,
+    mqtt_client: &MqttClient,
+    topics: &[(String, QoS)],
+) -> anyhow::Result<()> {
+    if topics.is_empty() {
+        return Ok(());
+    }
+    for (topic, qos) in topics.iter() {
+        info!("Subscribing to topic '{}' on broker '{}' with QoS {}", topic, broker_name, *qos as u8);
+    }
+    mqtt_client
+        .subscribe_many(topics)
+        .await?;
+    Ok(())
 }
 
-async fn init_mqtt(config: &MqttConfig) -> anyhow::Result<(MqttAsyncClient, MqttEventLoop)> {
-    let mut options = MqttOptions::new(&config.client_id, &config.host, config.port);
-    if let Some(connect_timeout) = config.connect_timeout {
-        options.set_connection_timeout(connect_timeout.as_secs());
+/// Everything built from the config that's needed to route an incoming
+/// message; swapped out as a whole when the config is reloaded.
+struct Router {
+    mappings: Vec<Arc<Mapping>>,
+    match_mode: MatchMode,
+    databases: Arc<Vec<Database>>,
+    /// The topic filters to subscribe to on each broker, by name; these
+    /// include any shared subscription prefix.
+    topics: HashMap<String, Vec<(String, QoS)>>,
+}
+
+impl Router {
+    fn topics(&self, broker_name: &str) -> &[(String, QoS)] {
+        self.topics.get(broker_name).map(Vec::as_slice).unwrap_or_default()
     }
-    if let Some(keep_alive) = config.keep_alive {
-        options.set_keep_alive(keep_alive);
+}
+
+/// Builds a router from the config; the databases of the router it replaces,
+/// if any, hand their spools over to the new ones.
+/// Sets up the mappings and databases, taking over what's worth keeping from
+/// `previous`, the router being replaced on a reload.
+async fn init_router(config: &Config, previous: Option<&Router>) -> anyhow::Result<Router> {
+    let mut mappings: Vec<Mapping> = config
+        .mappings
+        .iter()
+        .map(|mapping| Mapping::try_from((mapping, &config.databases[..], &config.brokers[..])))
+        .collect::<anyhow::Result<Vec<Mapping>>>()?;
+
+    // Mappings sharing a topic filter share a subscription on each broker
+    // they receive from, which gets the highest QoS any of them asks for.
+    let mut topics = HashMap::new();
+    for broker in config.brokers.iter() {
+        let mut broker_topics: BTreeMap<String, QoS> = BTreeMap::new();
+        for (config_mapping, mapping) in config
+            .mappings
+            .iter()
+            .zip(mappings.iter())
+            .filter(|(_, mapping)| mapping.receives_from(broker.name()))
+        {
+            let qos = broker_topics
+                .entry(broker.subscription_filter(&config_mapping.topic))
+                .or_insert(mapping.qos);
+            if mapping.qos > *qos {
+                *qos = mapping.qos;
+            }
+        }
+        topics.insert(broker.name().to_string(), broker_topics.into_iter().collect());
     }
-    if let Some(ca_file) = &config.ca_file {
-        let ca = fs::read(ca_file).await?;
-        options.set_transport(Transport::Tls(TlsConfiguration::Simple {
-            ca,
-            alpn: None,
-            client_auth: if let Some(MqttAuth::Certificate {
-                cert_file,
-                private_key_file,
-            }) = &config.auth
-            {
-                let cert = fs::read(cert_file).await?;
-                let private_key = fs::read(private_key_file).await?;
-                Some((cert, Key::RSA(private_key)))
-            } else {
-                None
-            },
-        }));
+
+    // Stable sort, so mappings with the same order keep their config order.
+    mappings.sort_by_key(|mapping| mapping.order);
+    if let Some(previous) = previous {
+        carry_over_retained(&mut mappings, &previous.mappings);
     }
 
-    if let Some(MqttAuth::UserPass { username, password }) = &config.auth {
-        options.set_credentials(username, password);
+    let mut tag_names = config
+        .mappings
+        .iter()
+        .flat_map(|mapping| mapping.tags.keys().cloned())
+        .collect::<Vec<String>>();
+    tag_names.sort();
+    tag_names.dedup();
+
+    let mut databases = Vec::new();
+    for database in config.databases.iter() {
+        let previous_databases = previous.map(|previous| &previous.databases[..]).unwrap_or_default();
+        databases.push(init_db(database, &tag_names, previous_databases).await?);
     }
 
-    Ok(MqttAsyncClient::new(options, 100))
+    Ok(Router {
+        mappings: mappings.into_iter().map(Arc::new).collect(),
+        match_mode: config.match_mode,
+        databases: Arc::new(databases),
+        topics,
+    })
 }
 
-fn init_db(config: &ConfigDatabase) -> anyhow::Result<Database> {
-    match config {
-        ConfigDatabase::Influxdb { url, auth, db_name, measurement } => {
-            let mut client = InfluxClient::new(url, db_name);
-            if let Some(UserAuth { username, password }) = auth {
-                client = client.with_auth(username, password);
+async fn reload_config(
+    config_filename: &Path,
+    mqtt_clients: &[(String, MqttClient)],
+    router_sender: &watch::Sender<Arc<Router>>,
+) -> anyhow::Result<()> {
+    let config = Config::parse(config_filename)?;
+
+    // Connections are only made at startup, and subscriptions are looked up
+    // by broker name, so the brokers have to stay the same.
+    let broker_names = config.brokers.iter().map(|broker| broker.name()).collect::<HashSet<&str>>();
+    let running_names = mqtt_clients.iter().map(|(name, _)| name.as_str()).collect::<HashSet<&str>>();
+    if broker_names != running_names {
+        Err(anyhow!("Brokers can't be added, removed or renamed without a restart"))?;
+    }
+
+    let old_router = Arc::clone(&router_sender.borrow());
+    let router = init_router(&config, Some(&old_router)).await?;
+
+    // Work out every broker's changes before making any of them.
+    // Subscribing again to a topic already subscribed to just updates its QoS.
+    let changes = mqtt_clients
+        .iter()
+        .map(|(broker_name, mqtt_client)| {
+            let old_topics = old_router.topics(broker_name).iter().cloned().collect::<HashMap<String, QoS>>();
+            let new_topics = router
+                .topics(broker_name)
+                .iter()
+                .filter(|(topic, qos)| old_topics.get(topic) != Some(qos))
+                .cloned()
+                .collect::<Vec<(String, QoS)>>();
+            let removed_topics = old_topics
+                .into_keys()
+                .filter(|topic| !router.topics(broker_name).iter().any(|(new_topic, _)| new_topic == topic))
+                .collect::<Vec<String>>();
+            (broker_name, mqtt_client, new_topics, removed_topics)
+        })
+        .collect::<Vec<_>>();
+
+    // A broker that fails to take a change keeps the rest of the reload from
+    // being undone; it's logged, and the new config goes live regardless.
+    for (broker_name, mqtt_client, new_topics, removed_topics) in changes {
+        if let Err(err) = init_subscriptions(broker_name, mqtt_client, &new_topics).await {
+            error!("Failed to subscribe on broker '{}': {}", broker_name, err);
+        }
+        for topic in removed_topics {
+            info!("Unsubscribing from topic '{}' on broker '{}'", topic, broker_name);
+            if let Err(err) = mqtt_client.unsubscribe(&topic).await {
+                error!("Failed to unsubscribe from topic '{}' on broker '{}': {}", topic, broker_name, err);
             }
-            Ok(Database {
-                client,
-                measurement: measurement.clone(),
-            })
         }
     }
+
+    // The old databases flush whatever they have queued once the last
+    // in-flight message holding on to them is done.
+    drop(old_router);
+    router_sender.send_replace(Arc::new(router));
+    Ok(())
 }
 
-async fn init_subscriptions(
-    mqtt_client: &mut MqttAsyncClient,
-    topics: &Vec<&String>,
+async fn run_reloader(
+    config_filename: PathBuf,
+    mqtt_clients: Vec<(String, MqttClient)>,
+    router_sender: watch::Sender<Arc<Router>>,
 ) -> anyhow::Result<()> {
-    let topics: Vec<SubscribeFilter> = topics
-        .iter()
-        .map(|topic| {
-            info!("Subscribing to topic '{}'", topic);
-            SubscribeFilter::new((*topic).clone(), QoS::AtLeastOnce)
-        })
-        .collect();
-    mqtt_client
-        .subscribe_many(topics)
-        .await?;
+    let mut hangups = signal(SignalKind::hangup())?;
+    while hangups.recv().await.is_some() {
+        info!("Reloading config from {}", config_filename.display());
+        match reload_config(&config_filename, &mqtt_clients, &router_sender).await {
+            Ok(_) => info!("Reloaded config; changes to the MQTT or logging settings require a restart"),
+            Err(err) => error!("Failed to reload config; keeping the current one: {}", err),
+        }
+    }
     Ok(())
 }
 
-async fn handle_publish(
-    publish: &Publish,
-    mapping: Arc<Mapping>,
-    databases: Arc<Vec<Database>>,
-) -> anyhow::Result<()> {
-    debug!("Got publish: {:?}; {:?}", publish, publish.payload);
+fn to_point(publish: &Message, broker_name: &str, mapping: &Mapping) -> anyhow::Result<Point> {
+    debug!("Got publish from broker '{}': {:?}; {:?}", broker_name, publish, publish.payload);
 
+    let variables = Variables {
+        broker: Some(broker_name),
+        content_type: publish.content_type.as_deref(),
+        user_properties: &publish.user_properties,
+    };
     let reference_values = publish
         .topic
         .split("/")
@@ -127,20 +237,37 @@ async fn handle_publish(
             _ => None,
         })
         .collect::<Vec<&str>>();
-    let field_name = mapping.field_name.interpolate(&reference_values)?;
+    let measurement = mapping
+        .measurement
+        .as_ref()
+        .map(|measurement| measurement.interpolate(&reference_values, &variables))
+        .transpose()?;
 
     let payload = String::from_utf8(Vec::from(publish.payload.as_ref()))
         .map_err(|err| anyhow!("Invalid payload value: {}", err))?;
-    let (influx_value, timestamp) = match &mapping.payload {
-        Payload::Raw => (payload.to_influx_type(mapping.value_type)?, None),
-        Payload::Json { value_field_selector, timestamp_field_selector } => {
+    let (fields, timestamp) = match &mapping.payload {
+        Payload::Raw => {
+            let fields = mapping
+                .fields
+                .iter()
+                .map(|field| Ok((field.name.interpolate(&reference_values, &variables)?, payload.to_influx_type(field.value_type)?)))
+                .collect::<anyhow::Result<Vec<(String, Type)>>>()?;
+            (fields, None)
+        },
+        Payload::Json { timestamp_field_selector } => {
             let payload_root: JsonValue = serde_json::from_str(&payload)
                 .map_err(|err| anyhow!("Failed to parse payload as JSON: {}", err))?;
-            let influx_value = value_field_selector
-                .find(&payload_root)
-                .next()
-                .ok_or_else(|| anyhow!("Couldn't find value in payload on topic {}", publish.topic))
-                .and_then(|value| value.to_influx_type(mapping.value_type))?;
+            let mut fields = Vec::new();
+            for field in mapping.fields.iter() {
+                let field_name = field.name.interpolate(&reference_values, &variables)?;
+                match field.selector.as_ref().and_then(|selector| selector.find(&payload_root).next()) {
+                    Some(value) => fields.push((field_name, value.to_influx_type(field.value_type)?)),
+                    None => debug!("Couldn't find field {} in payload on topic {}", field_name, publish.topic),
+                }
+            }
+            if fields.is_empty() {
+                Err(anyhow!("Couldn't find value in payload on topic {}", publish.topic))?;
+            }
             let timestamp = timestamp_field_selector
                 .as_ref()
                 .map(|selector| selector
@@ -154,7 +281,7 @@ async fn handle_publish(
                     )
                 )
                 .transpose()?;
-            (influx_value, timestamp)
+            (fields, timestamp)
         },
     };
 
@@ -164,118 +291,400 @@ async fn handle_publish(
         .as_nanos()
     );
 
-    for database in databases.iter() {
-        let mut query = Timestamp::Nanoseconds(timestamp)
-            .into_query(&database.measurement)
-            .add_field(&field_name, influx_value.clone());
-        for tag in mapping.tags.iter() {
+    let tags = mapping
+        .tags
+        .iter()
+        .map(|tag| {
             let value = match &tag.1 {
                 TagValue::Literal(v) => v.clone(),
-                TagValue::InterpolatedStr(interp) => Type::Text(interp.interpolate(&reference_values)?),
+                TagValue::InterpolatedStr(interp) => Type::Text(interp.interpolate(&reference_values, &variables)?),
             };
-            query = query.add_tag(&tag.0, value);
-        }
+            Ok((tag.0.clone(), value))
+        })
+        .collect::<anyhow::Result<Vec<(String, Type)>>>()?;
+    Ok(Point {
+        measurement,
+        timestamp,
+        fields,
+        tags,
+    })
+}
 
-        database
-            .client
-            .query(&query)
-            .await
-            .map_err(|err| anyhow!("Failed to write to DB: {}", err))?;
-        debug!("wrote to influx: {:?}", query);
+/// Waits for every database the mapping writes to to store the point; with
+/// `until_stored`, a database that's down is waited on until it's back.
+async fn write_point(point: Point, mapping: &Mapping, databases: &[Database], until_stored: bool) -> anyhow::Result<()> {
+    debug!("writing point to databases: {:?}", point);
+    let writes = databases
+        .iter()
+        .filter(|database| mapping.writes_to(database.name.as_deref()))
+        .map(|database| {
+            let point = point.clone();
+            async move {
+                if until_stored {
+                    database.write_until_stored(point).await
+                } else {
+                    database.write(point).await
+                }
+            }
+        });
+    for result in join_all(writes).await {
+        match result {
+            // The database has already logged why; sending the message
+            // again wouldn't change anything.
+            Ok(_) | Err(WriteError::Permanent(_)) => (),
+            Err(err) => Err(anyhow!("Failed to write to DB: {}", err))?,
+        }
     }
-
     Ok(())
 }
 
-fn find_mapping<'a>(mappings: &'a Vec<Arc<Mapping>>, topic: &String) -> Option<&'a Arc<Mapping>> {
-    let levels: Vec<&str> = topic.split("/").collect();
-    mappings.iter().find(|mapping| {
-        let mut iter = levels.iter();
-        for expected_level in mapping.topic.iter() {
-            let maybe_cur_level = iter.next();
-            match (expected_level, maybe_cur_level) {
-                (TopicLevel::SingleWildcard, Some(_)) => (), // current level exists and anything matches
-                (TopicLevel::MultiWildcard, _) => return true, // rest of topic, if any, will match no matter what
-                (TopicLevel::Literal(expected_literal), Some(cur_level))
-                    if expected_literal == cur_level =>
-                {
-                    ()
-                } // current level matches
-                _ => return false, // current level doesn't match or doesn't exist
+fn find_mappings(
+    mappings: &[Arc<Mapping>],
+    message: &Message,
+    broker_name: &str,
+    match_mode: MatchMode,
+) -> Vec<Arc<Mapping>> {
+    let mut found = Vec::new();
+    for mapping in mappings.iter().filter(|mapping| {
+        mapping.receives_from(broker_name)
+            && mapping.matches(&message.topic)
+            && mapping.accepts_content_type(message.content_type.as_deref())
+    }) {
+        found.push(Arc::clone(mapping));
+        if mapping.stops(match_mode) {
+            break;
+        }
+    }
+    found
+}
+
+/// Writes a message to the databases of every mapping it matches, and then
+/// acknowledges it once they've all stored it.  The broker only sends an
+/// unacknowledged message again after a reconnect, so until then, writes
+/// that fail are retried rather than left hanging.  A message that needs an
+/// ack comes with its place in the ack order: the previous message's ack to
+/// wait for, and a way to signal its own.
+async fn handle_publish(
+    publish: Message,
+    broker_name: Arc<str>,
+    router: Arc<Router>,
+    mqtt_client: MqttClient,
+    ack_order: Option<(oneshot::Receiver<()>, oneshot::Sender<()>)>,
+) {
+    let found_mappings = find_mappings(&router.mappings, &publish, &broker_name, router.match_mode);
+    if found_mappings.is_empty() {
+        warn!("Topic {} not found in mappings", publish.topic);
+    }
+
+    for mapping in found_mappings {
+        if !mapping.claim_retained(&broker_name, &publish.topic, publish.retain) {
+            debug!("Skipping retained message on topic {}", publish.topic);
+            continue;
+        }
+
+        // A message that can't be turned into a point never will be, so it
+        // still gets acknowledged rather than redelivered over and over.
+        match to_point(&publish, &broker_name, &mapping) {
+            Ok(point) => {
+                if let Err(err) = write_point(point, &mapping, &router.databases, publish.needs_ack()).await {
+                    warn!("{}", err);
+                    if publish.retain {
+                        mapping.release_retained(&broker_name, &publish.topic);
+                    }
+                }
             }
+            Err(err) => warn!("{}", err),
         }
-        iter.next().is_none() // only matches if we consumed all topic levels
-    })
+    }
+
+    if let Some((previous_ack, ack_done)) = ack_order {
+        // Whether or not the previous message got acknowledged, it's done.
+        let _ = previous_ack.await;
+        if let Err(err) = mqtt_client.ack(&publish).await {
+            warn!("Failed to acknowledge message on topic {}: {}", publish.topic, err);
+        }
+        let _ = ack_done.send(());
+    }
 }
 
 async fn run_event_loop(
     mut event_loop: MqttEventLoop,
-    mappings: Vec<Mapping>,
-    databases: Vec<Database>,
+    mqtt_client: MqttClient,
+    router_receiver: watch::Receiver<Arc<Router>>,
+    mqtt_config: &MqttConfig,
 ) {
-    let mappings = mappings.into_iter().map(Arc::new).collect();
-    let databases = Arc::new(databases);
+    let broker_name: Arc<str> = Arc::from(mqtt_config.name());
+    let reconnect = &mqtt_config.reconnect;
+    let mut connected = false;
+    let mut ever_connected = false;
+    let mut failed_attempts = 0;
 
+    // Acks have to be sent in the order the messages arrived in, so each
+    // message that needs one waits for the one before it.  The first one has
+    // nothing to wait for, which a receiver without a sender gives us.
+    let (_, mut previous_ack) = oneshot::channel();
     loop {
         match event_loop.poll().await {
-            Ok(Event::Incoming(Packet::Publish(publish))) => {
-                if let Some(mapping) = find_mapping(&mappings, &publish.topic) {
-                    let mapping = Arc::clone(mapping);
-                    let databases = Arc::clone(&databases);
-                    tokio::spawn(async move {
-                        if let Err(err) = handle_publish(&publish, mapping, databases).await {
-                            warn!("{}", err);
+            Ok(MqttEvent::Connected { session_present }) => {
+                let verb = if ever_connected { "Reconnected" } else { "Connected" };
+                info!("{} to MQTT broker '{}'", verb, broker_name);
+                // A session the broker kept from before still has our
+                // subscriptions, unless this process hasn't made them yet.
+                let topics = if !session_present || !ever_connected {
+                    router_receiver.borrow().topics(&broker_name).to_vec()
+                } else {
+                    Vec::new()
+                };
+                let status = mqtt_config
+                    .status
+                    .as_ref()
+                    .map(|status| (status.topic.clone(), status.online.clone()));
+                let broker_name = Arc::clone(&broker_name);
+                let mqtt_client = mqtt_client.clone();
+                tokio::spawn(async move {
+                    if let Err(err) = init_subscriptions(&broker_name, &mqtt_client, &topics).await {
+                        error!("Failed to subscribe on broker '{}': {}", broker_name, err);
+                    }
+                    if let Some((topic, online)) = status {
+                        if let Err(err) = mqtt_client.publish_retained(&topic, &online).await {
+                            warn!("Failed to publish status to '{}': {}", topic, err);
                         }
-                    });
+                    }
+                });
+                connected = true;
+                ever_connected = true;
+                failed_attempts = 0;
+            }
+            Ok(MqttEvent::Message(publish)) => {
+                let router = Arc::clone(&router_receiver.borrow());
+                // QoS 0 messages stay out of it, so they never pile up
+                // behind a QoS 1 or 2 message whose write is stuck.
+                let ack_order = publish.needs_ack().then(|| {
+                    let (ack_done, next_previous_ack) = oneshot::channel();
+                    (std::mem::replace(&mut previous_ack, next_previous_ack), ack_done)
+                });
+                tokio::spawn(handle_publish(
+                    publish,
+                    Arc::clone(&broker_name),
+                    router,
+                    mqtt_client.clone(),
+                    ack_order,
+                ));
+            }
+            Ok(MqttEvent::Other) => (),
+            Err(err) => {
+                failed_attempts += 1;
+                let delay = backoff_delay(reconnect.initial_delay, reconnect.max_delay, failed_attempts);
+                if connected {
+                    warn!("Lost connection to MQTT broker '{}'; reconnecting: {}", broker_name, err);
+                } else if failed_attempts == 1 {
+                    warn!("Unable to connect to MQTT broker '{}'; will keep trying: {}", broker_name, err);
                 } else {
-                    warn!("Topic {} not found in mappings", publish.topic);
+                    debug!("Connection attempt {} failed; retrying in {:?}: {}", failed_attempts, delay, err);
                 }
+                connected = false;
+                sleep(delay).await;
             }
-            Ok(_) => (),
-            Err(err) => warn!("Error from MQTT loop: {:#?}", err),
         }
     }
 }
 
+/// Validates the config file without connecting to anything, reporting
+/// every problem found rather than stopping at the first one.
+fn check_config(config_filename: &Path) -> Vec<String> {
+    let config = match Config::parse(config_filename) {
+        Ok(config) => config,
+        Err(err) => return vec![format!("{}: {}", config_filename.display(), err)],
+    };
+
+    config
+        .mappings
+        .iter()
+        .enumerate()
+        .flat_map(|(i, mapping)| {
+            Mapping::try_from((mapping, &config.databases[..], &config.brokers[..]))
+                .err()
+                .map(|err| format!("mappings[{}] (topic '{}'): {}", i, mapping.topic, err))
+        })
+        .collect()
+}
+
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    let config_filename = env::args()
-        .nth(1)
-        .ok_or_else(|| anyhow!("Missing argument 'config filename'"))?;
-    let config = Config::parse(&config_filename)?;
+    let cli = Cli::parse_args();
+    let config_filename = cli.config.clone();
+
+    match cli.command() {
+        Command::Run => (),
+        Command::Check => {
+            let errors = check_config(&config_filename);
+            if errors.is_empty() {
+                println!("{}: OK", config_filename.display());
+                return Ok(());
+            }
+            for error in errors.iter() {
+                eprintln!("{}", error);
+            }
+            std::process::exit(1);
+        }
+        Command::PrintConfig => {
+            let config = Config::parse(&config_filename)?;
+            print!("{}", serde_yaml::to_string(&config)?);
+            return Ok(());
+        }
+        Command::Version => {
+            println!("{} {}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"));
+            return Ok(());
+        }
+    }
+
+    let config = Config::parse(&config_filename)
+        .map_err(|err| anyhow!("Failed to load config from {}: {}", config_filename.display(), err))?;
 
     let logger_env = env_logger::Env::new()
         .filter("MQTT2DB_LOG")
         .write_style("MQTT2DB_LOG_STYLE");
     let mut logger_builder = env_logger::Builder::from_env(logger_env);
-    if let Some(log_level) = config.log_level {
+    if let Some(log_level) = cli.log_level.or(config.log_level) {
         logger_builder.filter_level(log_level);
     }
     logger_builder.init();
 
-    let mappings: Vec<Mapping> = config
-        .mappings
-        .iter()
-        .map(Mapping::try_from)
-        .collect::<anyhow::Result<Vec<Mapping>>>()?;
+    let router = init_router(&config, None).await?;
 
-    let (mut mqtt_client, mqtt_event_loop) = init_mqtt(&config.mqtt).await?;
-    init_subscriptions(
-        &mut mqtt_client,
-        &config
-            .mappings
-            .iter()
-            .map(|mapping| &mapping.topic)
-            .collect(),
-    )
-    .await?;
+    let (router_sender, router_receiver) = watch::channel(Arc::new(router));
 
-    let databases = config.databases
-        .iter()
-        .map(init_db)
-        .collect::<anyhow::Result<Vec<Database>>>()?;
+    // Subscriptions are made once each connection is up.
+    let mut mqtt_clients = Vec::new();
+    let mut event_loops = Vec::new();
+    for broker in config.brokers.iter() {
+        let (mqtt_client, mqtt_event_loop) = init_mqtt(broker)
+            .await
+            .map_err(|err| anyhow!("Broker '{}': {}", broker.name(), err))?;
+        mqtt_clients.push((broker.name().to_string(), mqtt_client.clone()));
+        event_loops.push(run_event_loop(mqtt_event_loop, mqtt_client, router_receiver.clone(), broker));
+    }
 
-    run_event_loop(mqtt_event_loop, mappings, databases).await;
+    tokio::spawn(async move {
+        if let Err(err) = run_reloader(config_filename, mqtt_clients, router_sender).await {
+            error!("Config reloading is unavailable: {}", err);
+        }
+    });
+
+    join_all(event_loops).await;
 
     Ok(())
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_util::temp_path;
+
+    fn write_config(name: &str, yaml: &str) -> anyhow::Result<PathBuf> {
+        let path = PathBuf::from(temp_path(&format!("{}.yaml", name)));
+        std::fs::write(&path, yaml)?;
+        Ok(path)
+    }
+
+    #[tokio::test]
+    async fn subscription_qos() -> anyhow::Result<()> {
+        let path = write_config(
+            "qos",
+            r#"
+            mqtt: { host: localhost, port: 1883, clientId: mqtt2db }
+            databases: []
+            mappings:
+              - { topic: 'home/+/temperature', qos: 0, fieldName: value, valueType: float, tags: {} }
+              - { topic: 'home/+/temperature', qos: 2, fieldName: celsius, valueType: float, tags: {} }
+              - { topic: 'home/+/temperature', fieldName: kelvin, valueType: float, tags: {} }
+              - { topic: 'home/+/humidity', qos: 0, fieldName: value, valueType: float, tags: {} }
+            "#,
+        )?;
+        let router = init_router(&Config::parse(&path)?, None).await?;
+        assert_eq!(
+            router.topics("localhost"),
+            &[
+                ("home/+/humidity".to_string(), QoS::AtMostOnce),
+                ("home/+/temperature".to_string(), QoS::ExactlyOnce),
+            ]
+        );
+
+        std::fs::remove_file(&path)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn mapping_selection() -> anyhow::Result<()> {
+        // Each mapping's order doubles as its name here.
+        let path = write_config(
+            "mapping-selection",
+            r#"
+            mqtt: { host: localhost, port: 1883, clientId: mqtt2db }
+            databases: []
+            mappings:
+              - { topic: 'home/#', order: 10, fieldName: value, valueType: float, tags: {} }
+              - { topic: 'home/+/temperature', fieldName: value, valueType: float, tags: {} }
+              - { topic: 'home/+/humidity', order: 5, stop: true, fieldName: value, valueType: float, tags: {} }
+              - { topic: 'home/kitchen/+', order: -1, stop: false, fieldName: value, valueType: float, tags: {} }
+            "#,
+        )?;
+        let router = init_router(&Config::parse(&path)?, None).await?;
+        let found = |topic: &str, match_mode: MatchMode| {
+            find_mappings(&router.mappings, &Message::new(topic, "1"), "localhost", match_mode)
+                .iter()
+                .map(|mapping| mapping.order)
+                .collect::<Vec<i32>>()
+        };
+
+        // Mappings are tried in order, and the first one that matches ends
+        // the search unless it says otherwise.
+        assert_eq!(found("home/kitchen/temperature", MatchMode::First), vec![-1, 0]);
+        assert_eq!(found("home/hall/temperature", MatchMode::First), vec![0]);
+        assert_eq!(found("home/hall/door", MatchMode::First), vec![10]);
+        assert_eq!(found("garden/temperature", MatchMode::First), Vec::<i32>::new());
+
+        // With matchMode: all, every match applies, until one that says to
+        // stop.
+        assert_eq!(found("home/kitchen/temperature", MatchMode::All), vec![-1, 0, 10]);
+        assert_eq!(found("home/kitchen/humidity", MatchMode::All), vec![-1, 5]);
+        assert_eq!(found("home/hall/door", MatchMode::All), vec![10]);
+
+        std::fs::remove_file(&path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn config_checking() -> anyhow::Result<()> {
+        let path = write_config(
+            "check",
+            r#"
+            mqtt: { host: localhost, port: 1883, clientId: mqtt2db }
+            databases: []
+            mappings:
+              - { topic: 'home/+/temperature', fieldName: value, valueType: float, tags: {} }
+              - { topic: 'home/#/temperature', fieldName: value, valueType: float, tags: {} }
+              - { topic: 'home/+', measurement: '$2', fieldName: value, valueType: float, tags: {} }
+              - { topic: 'home/+', brokers: [elsewhere], fieldName: value, valueType: float, tags: {} }
+            "#,
+        )?;
+        let errors = check_config(&path);
+        assert_eq!(3, errors.len(), "{:?}", errors);
+        assert!(errors[0].starts_with("mappings[1] (topic 'home/#/temperature'): "), "{}", errors[0]);
+        assert!(errors[1].starts_with("mappings[2] (topic 'home/+'): "), "{}", errors[1]);
+        assert_eq!(errors[2], "mappings[3] (topic 'home/+'): Topic 'home/+' refers to unknown broker 'elsewhere'");
+        std::fs::remove_file(&path)?;
+
+        let path = write_config(
+            "check-ok",
+            "{ mqtt: { host: localhost, port: 1883, clientId: mqtt2db }, databases: [], mappings: [] }",
+        )?;
+        assert!(check_config(&path).is_empty());
+
+        std::fs::write(&path, "mqtt: [")?;
+        assert_eq!(1, check_config(&path).len());
+
+        std::fs::remove_file(&path)?;
+        Ok(())
+    }
+}
@@ -16,9 +16,15 @@
 
 use influxdb::Type;
 use jsonpath::Selector;
+use rumqttc::QoS;
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
 use std::{convert::TryFrom, fmt};
 
-use crate::config::{Mapping as ConfigMapping, Payload as ConfigPayload, TagValue as ConfigTagValue};
+use crate::config::{
+    Database as ConfigDatabase, Mapping as ConfigMapping, MatchMode, MqttConfig, Payload as ConfigPayload,
+    RetainedPolicy, TagValue as ConfigTagValue,
+};
 use crate::interpolate::{InterpolatedName, InterpolatedNamePart};
 use crate::value::{ToInfluxType, ValueType};
 
@@ -55,7 +61,7 @@ impl TryFrom<&ConfigTagValue> for TagValue {
         match tag_value.r#type {
             ValueType::Text => {
                 let interp = InterpolatedName::try_from(tag_value.value.as_str())?;
-                match interp.parts.get(0) {
+                match interp.parts.first() {
                     Some(InterpolatedNamePart::Literal(literal)) if interp.parts.len() == 1 => {
                         Ok(TagValue::Literal(Type::Text(literal.clone())))
                     }
@@ -70,7 +76,6 @@ impl TryFrom<&ConfigTagValue> for TagValue {
 pub enum Payload {
     Raw,
     Json {
-        value_field_selector: Selector,
         timestamp_field_selector: Option<Selector>,
     },
 }
@@ -85,22 +90,123 @@ impl fmt::Debug for Payload {
     }
 }
 
+pub struct Field {
+    pub name: InterpolatedName,
+    pub value_type: ValueType,
+    /// Where to find the value in a JSON payload; raw payloads have none.
+    pub selector: Option<Selector>,
+}
+
+impl fmt::Debug for Field {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Field")
+            .field("name", &self.name)
+            .field("value_type", &self.value_type)
+            .finish_non_exhaustive()
+    }
+}
+
 #[derive(Debug)]
 pub struct Mapping {
     pub topic: Vec<TopicLevel>,
+    pub qos: QoS,
+    /// Only apply to messages with this MQTT 5 content type.
+    pub content_type: Option<String>,
     pub payload: Payload,
-    pub field_name: InterpolatedName,
-    pub value_type: ValueType,
+    pub measurement: Option<InterpolatedName>,
+    pub fields: Vec<Field>,
     pub tags: Vec<(String, TagValue)>,
+    pub databases: Option<Vec<String>>,
+    pub brokers: Option<Vec<String>>,
+    pub retained: RetainedPolicy,
+    /// The broker and topic of each retained message stored, or being
+    /// stored, so far; shared with the mapping replacing this one on a
+    /// reload, so that `retained: once` holds for the life of the process.
+    retained_claimed: Arc<Mutex<HashSet<(String, String)>>>,
+    pub order: i32,
+    pub stop: Option<bool>,
+}
+
+impl Mapping {
+    pub fn matches(&self, topic: &str) -> bool {
+        let mut iter = topic.split('/');
+        for expected_level in self.topic.iter() {
+            let maybe_cur_level = iter.next();
+            match (expected_level, maybe_cur_level) {
+                (TopicLevel::SingleWildcard, Some(_)) => (), // current level exists and anything matches
+                (TopicLevel::MultiWildcard, _) => return true, // rest of topic, if any, will match no matter what
+                (TopicLevel::Literal(expected_literal), Some(cur_level))
+                    if expected_literal == cur_level =>
+                {} // current level matches
+                _ => return false, // current level doesn't match or doesn't exist
+            }
+        }
+        iter.next().is_none() // only matches if we consumed all topic levels
+    }
+
+    pub fn accepts_content_type(&self, content_type: Option<&str>) -> bool {
+        match &self.content_type {
+            None => true,
+            Some(expected) => content_type == Some(expected.as_str()),
+        }
+    }
+
+    /// Whether a message should be stored, as far as its being retained
+    /// goes.  With `retained: once`, the first retained message on a topic
+    /// claims it, so that others arriving while it's being written are
+    /// skipped; if it can't be stored after all, `release_retained` gives
+    /// the topic back.
+    pub fn claim_retained(&self, broker_name: &str, topic: &str, retain: bool) -> bool {
+        match (self.retained, retain) {
+            (_, false) | (RetainedPolicy::Store, true) => true,
+            (RetainedPolicy::Ignore, true) => false,
+            (RetainedPolicy::Once, true) => self
+                .retained_claimed
+                .lock()
+                .unwrap()
+                .insert((broker_name.to_string(), topic.to_string())),
+        }
+    }
+
+    pub fn release_retained(&self, broker_name: &str, topic: &str) {
+        if self.retained == RetainedPolicy::Once {
+            self.retained_claimed
+                .lock()
+                .unwrap()
+                .remove(&(broker_name.to_string(), topic.to_string()));
+        }
+    }
+
+    /// Whether no further mappings should be applied after this one.
+    pub fn stops(&self, match_mode: MatchMode) -> bool {
+        self.stop.unwrap_or(match_mode == MatchMode::First)
+    }
+
+    pub fn receives_from(&self, broker_name: &str) -> bool {
+        match &self.brokers {
+            None => true,
+            Some(names) => names.iter().any(|name| name == broker_name),
+        }
+    }
+
+    pub fn writes_to(&self, database_name: Option<&str>) -> bool {
+        match (&self.databases, database_name) {
+            (None, _) => true,
+            (Some(names), Some(database_name)) => names.iter().any(|name| name == database_name),
+            (Some(_), None) => false,
+        }
+    }
 }
 
-impl TryFrom<&ConfigMapping> for Mapping {
+impl TryFrom<(&ConfigMapping, &[ConfigDatabase], &[MqttConfig])> for Mapping {
     type Error = anyhow::Error;
-    fn try_from(mapping: &ConfigMapping) -> Result<Self, Self::Error> {
+    fn try_from(
+        (mapping, databases, brokers): (&ConfigMapping, &[ConfigDatabase], &[MqttConfig]),
+    ) -> Result<Self, Self::Error> {
         let topic = mapping
             .topic
             .split("/")
-            .map(|level| TopicLevel::try_from(level))
+            .map(TopicLevel::try_from)
             .collect::<anyhow::Result<Vec<TopicLevel>>>()?;
         let pre_multi_levels: Vec<&TopicLevel> = topic
             .iter()
@@ -113,34 +219,88 @@ impl TryFrom<&ConfigMapping> for Mapping {
             ))?;
         }
 
+        let qos = match mapping.qos {
+            None => QoS::AtLeastOnce,
+            Some(qos) => rumqttc::qos(qos)
+                .map_err(|_| anyhow!("Topic '{}' has invalid QoS {}; must be 0, 1 or 2", mapping.topic, qos))?,
+        };
+
         let max_interp_ref = topic
             .iter()
             .filter(|level| **level == TopicLevel::SingleWildcard)
             .count();
 
-        let field_name = match InterpolatedName::try_from(mapping.field_name.as_str()) {
+        let parse_field_name = |field_name: &str| match InterpolatedName::try_from(field_name) {
             Ok(name) if find_max_ref(&name) > max_interp_ref => Err(anyhow!(
                 "Topic '{}' has field name '{}' which has invalid references",
-                mapping.topic, mapping.field_name
+                mapping.topic, field_name
             )),
             Ok(name) => Ok(name),
             Err(err) => Err(err),
-        }?;
+        };
+        let parse_selector = |path: &str| {
+            Selector::new(path).map_err(|err| anyhow!("Value field path '{}' is invalid: {}'", path, err))
+        };
+        let field_name = mapping
+            .field_name
+            .as_ref()
+            .ok_or_else(|| anyhow!("Topic '{}' is missing a field name", mapping.topic));
+        let value_type = mapping
+            .value_type
+            .ok_or_else(|| anyhow!("Topic '{}' is missing a value type", mapping.topic));
+
+        let measurement = mapping
+            .measurement
+            .as_ref()
+            .map(|measurement| match InterpolatedName::try_from(measurement.as_str()) {
+                Ok(name) if find_max_ref(&name) > max_interp_ref => Err(anyhow!(
+                    "Topic '{}' has measurement '{}' which has invalid references",
+                    mapping.topic, measurement
+                )),
+                other => other,
+            })
+            .transpose()?;
+
+        let (payload, fields) = match &mapping.payload {
+            None => {
+                let field = Field {
+                    name: parse_field_name(field_name?)?,
+                    value_type: value_type?,
+                    selector: None,
+                };
+                (Payload::Raw, vec![field])
+            }
+            Some(ConfigPayload::Json { value_field_path, timestamp_field_path, fields }) => {
+                let mut parsed_fields = Vec::new();
+                match value_field_path {
+                    Some(path) => parsed_fields.push(Field {
+                        name: parse_field_name(field_name?)?,
+                        value_type: value_type?,
+                        selector: Some(parse_selector(path)?),
+                    }),
+                    None if mapping.field_name.is_some() || mapping.value_type.is_some() => Err(anyhow!(
+                        "Topic '{}' has a field name or value type but no value field path",
+                        mapping.topic
+                    ))?,
+                    None => (),
+                }
+                for field in fields.iter() {
+                    parsed_fields.push(Field {
+                        name: parse_field_name(&field.name)?,
+                        value_type: field.value_type,
+                        selector: Some(parse_selector(&field.path)?),
+                    });
+                }
+                if parsed_fields.is_empty() {
+                    Err(anyhow!("Topic '{}' has no fields to write", mapping.topic))?;
+                }
 
-        let payload = match &mapping.payload {
-            None => Payload::Raw,
-            Some(ConfigPayload::Json { value_field_path, timestamp_field_path }) => {
-                let value_field_selector = Selector::new(&value_field_path)
-                    .map_err(|err| anyhow!("Value field path '{}' is invalid: {}'", value_field_path, err))?;
                 let timestamp_field_selector = timestamp_field_path.as_ref()
                     .map(|path| Selector::new(path)
                         .map_err(|err| anyhow!("Timestamp field path '{}' is invalid: {}'", path, err))
                     )
                     .transpose()?;
-                Payload::Json {
-                    value_field_selector,
-                    timestamp_field_selector,
-                }
+                (Payload::Json { timestamp_field_selector }, parsed_fields)
             }
         };
 
@@ -159,16 +319,60 @@ impl TryFrom<&ConfigMapping> for Mapping {
             })
             .collect::<anyhow::Result<Vec<(String, TagValue)>>>()?;
 
+        if let Some(database_names) = &mapping.databases {
+            for database_name in database_names.iter() {
+                if !databases.iter().any(|database| database.name.as_ref() == Some(database_name)) {
+                    Err(anyhow!(
+                        "Topic '{}' refers to unknown database '{}'",
+                        mapping.topic, database_name
+                    ))?;
+                }
+            }
+        }
+        if let Some(broker_names) = &mapping.brokers {
+            for broker_name in broker_names.iter() {
+                if !brokers.iter().any(|broker| broker.name() == broker_name) {
+                    Err(anyhow!("Topic '{}' refers to unknown broker '{}'", mapping.topic, broker_name))?;
+                }
+            }
+        }
+
         Ok(Mapping {
             topic,
+            qos,
+            content_type: mapping.content_type.clone(),
             payload,
-            field_name,
-            value_type: mapping.value_type,
+            measurement,
+            fields,
             tags,
+            databases: mapping.databases.clone(),
+            brokers: mapping.brokers.clone(),
+            retained: mapping.retained,
+            retained_claimed: Arc::new(Mutex::new(HashSet::new())),
+            order: mapping.order,
+            stop: mapping.stop,
         })
     }
 }
 
+/// Hands each mapping the retained topics claimed by the one it replaces:
+/// the mapping in the same place among those for the same topic filter.
+pub fn carry_over_retained(mappings: &mut [Mapping], previous: &[Arc<Mapping>]) {
+    for i in 0..mappings.len() {
+        let place = mappings[..i]
+            .iter()
+            .filter(|mapping| mapping.topic == mappings[i].topic)
+            .count();
+        if let Some(replaced) = previous
+            .iter()
+            .filter(|mapping| mapping.topic == mappings[i].topic)
+            .nth(place)
+        {
+            mappings[i].retained_claimed = Arc::clone(&replaced.retained_claimed);
+        }
+    }
+}
+
 fn find_max_ref(name: &InterpolatedName) -> usize {
     name.parts.iter().fold(0, |max_ref, part| match part {
         InterpolatedNamePart::Reference(num) if *num > max_ref => *num,
@@ -178,27 +382,29 @@ fn find_max_ref(name: &InterpolatedName) -> usize {
 
 #[cfg(test)]
 mod test {
-    use std::collections::HashMap;
-
     use super::*;
+    use crate::interpolate::Variables;
+    use crate::test_util::mqtt_config;
+
+    fn parse_mapping(yaml: &str, databases: &[ConfigDatabase]) -> anyhow::Result<Mapping> {
+        let mapping: ConfigMapping = serde_yaml::from_str(yaml)?;
+        Mapping::try_from((&mapping, databases, &[][..]))
+    }
+
+    fn parse_topic(topic: &str) -> anyhow::Result<Mapping> {
+        parse_mapping(
+            &format!("{{ topic: '{}', fieldName: value, valueType: text, tags: {{}} }}", topic),
+            &[],
+        )
+    }
 
     #[test]
     fn mapping_parsing() -> anyhow::Result<()> {
         use TopicLevel::*;
 
-        fn mk_cfg_mapping(topic: &str) -> ConfigMapping {
-            ConfigMapping {
-                topic: topic.to_string(),
-                payload: None,
-                field_name: "".to_string(),
-                value_type: ValueType::Text,
-                tags: HashMap::new(),
-            }
-        }
-
         assert_eq!(
             vec![Literal("foo".to_string()), Literal("bar".to_string())],
-            Mapping::try_from(&mk_cfg_mapping("foo/bar"))?.topic
+            parse_topic("foo/bar")?.topic
         );
 
         assert_eq!(
@@ -207,7 +413,7 @@ mod test {
                 Literal("foo".to_string()),
                 Literal("bar".to_string())
             ],
-            Mapping::try_from(&mk_cfg_mapping("/foo/bar"))?.topic
+            parse_topic("/foo/bar")?.topic
         );
 
         assert_eq!(
@@ -216,7 +422,7 @@ mod test {
                 Literal("bar".to_string()),
                 Literal("".to_string())
             ],
-            Mapping::try_from(&mk_cfg_mapping("foo/bar/"))?.topic
+            parse_topic("foo/bar/")?.topic
         );
 
         assert_eq!(
@@ -225,7 +431,7 @@ mod test {
                 Literal("bar".to_string()),
                 MultiWildcard
             ],
-            Mapping::try_from(&mk_cfg_mapping("foo/bar/#"))?.topic
+            parse_topic("foo/bar/#")?.topic
         );
 
         assert_eq!(
@@ -234,7 +440,7 @@ mod test {
                 SingleWildcard,
                 Literal("bar".to_string())
             ],
-            Mapping::try_from(&mk_cfg_mapping("foo/+/bar"))?.topic
+            parse_topic("foo/+/bar")?.topic
         );
 
         assert_eq!(
@@ -244,13 +450,270 @@ mod test {
                 Literal("bar".to_string()),
                 MultiWildcard
             ],
-            Mapping::try_from(&mk_cfg_mapping("foo/+/bar/#"))?.topic
+            parse_topic("foo/+/bar/#")?.topic
         );
 
-        assert!(Mapping::try_from(&mk_cfg_mapping("foo/#/bar")).is_err());
-        assert!(Mapping::try_from(&mk_cfg_mapping("foo/bar#")).is_err());
-        assert!(Mapping::try_from(&mk_cfg_mapping("foo/bar+baz/quux")).is_err());
-        assert!(Mapping::try_from(&mk_cfg_mapping("foo/bar#baz/quux")).is_err());
+        assert!(parse_topic("foo/#/bar").is_err());
+        assert!(parse_topic("foo/bar#").is_err());
+        assert!(parse_topic("foo/bar+baz/quux").is_err());
+        assert!(parse_topic("foo/bar#baz/quux").is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn database_routing() -> anyhow::Result<()> {
+        let databases: Vec<ConfigDatabase> = serde_yaml::from_str(
+            r#"
+            - name: long-term
+              type: sqlite
+              path: long-term.db
+              table:
+                name: readings
+            - type: sqlite
+              path: unnamed.db
+              table:
+                name: readings
+            "#,
+        )?;
+
+        let parse = |extra: &str| {
+            parse_mapping(
+                &format!("{{ topic: foo/bar, fieldName: value, valueType: text, tags: {{}}, {} }}", extra),
+                &databases,
+            )
+        };
+
+        let mapping = parse("databases: ~")?;
+        assert!(mapping.writes_to(Some("long-term")));
+        assert!(mapping.writes_to(None));
+
+        let mapping = parse("databases: [long-term]")?;
+        assert!(mapping.writes_to(Some("long-term")));
+        assert!(!mapping.writes_to(Some("short-term")));
+        assert!(!mapping.writes_to(None));
+
+        assert!(parse("databases: [short-term]").is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn broker_filtering() -> anyhow::Result<()> {
+        assert!(parse_topic("foo/bar")?.receives_from("site-a"));
+
+        let brokers = [mqtt_config("name: site-a")?, mqtt_config("name: site-b")?];
+        let parse = |brokers_yaml: &str| {
+            let yaml = format!(
+                "{{ topic: foo/bar, brokers: {}, fieldName: value, valueType: text, tags: {{}} }}",
+                brokers_yaml
+            );
+            Mapping::try_from((&serde_yaml::from_str::<ConfigMapping>(&yaml)?, &[][..], &brokers[..]))
+        };
+
+        let mapping = parse("[site-a]")?;
+        assert!(mapping.receives_from("site-a"));
+        assert!(!mapping.receives_from("site-b"));
+
+        let err = parse("[site-a, site-c]").unwrap_err();
+        assert_eq!(err.to_string(), "Topic 'foo/bar' refers to unknown broker 'site-c'");
+
+        Ok(())
+    }
+
+    #[test]
+    fn measurement_parsing() -> anyhow::Result<()> {
+        let parse = |measurement: &str| {
+            parse_mapping(
+                &format!(
+                    "{{ topic: home/+/temperature, measurement: '{}', fieldName: value, valueType: float, tags: {{}} }}",
+                    measurement
+                ),
+                &[],
+            )
+        };
+
+        let mapping = parse("$1")?;
+        assert_eq!(
+            "kitchen",
+            mapping.measurement.unwrap().interpolate(&["kitchen"], &Variables::default())?
+        );
+
+        assert!(parse("$2").is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn json_fields_parsing() -> anyhow::Result<()> {
+        let parse = |yaml: &str| parse_mapping(yaml, &[]);
+
+        let mapping = parse(
+            r#"
+            topic: zigbee/+
+            payload:
+              type: json
+              fields:
+                - name: temperature
+                  path: $.temperature
+                  valueType: float
+                - name: $1_battery
+                  path: $.battery
+                  valueType: unsigned-integer
+            tags: {}
+            "#,
+        )?;
+        assert_eq!(2, mapping.fields.len());
+        assert_eq!("temperature", mapping.fields[0].name.interpolate(&["sensor"], &Variables::default())?);
+        assert_eq!("sensor_battery", mapping.fields[1].name.interpolate(&["sensor"], &Variables::default())?);
+
+        let mapping = parse(
+            r#"
+            topic: zigbee/+
+            payload:
+              type: json
+              valueFieldPath: $.humidity
+              fields:
+                - name: temperature
+                  path: $.temperature
+                  valueType: float
+            fieldName: humidity
+            valueType: float
+            tags: {}
+            "#,
+        )?;
+        assert_eq!(2, mapping.fields.len());
+
+        assert!(parse(
+            r#"
+            topic: zigbee/+
+            payload:
+              type: json
+            tags: {}
+            "#
+        )
+        .is_err());
+
+        assert!(parse(
+            r#"
+            topic: zigbee/+
+            payload:
+              type: json
+              fields:
+                - name: $2
+                  path: $.temperature
+                  valueType: float
+            tags: {}
+            "#
+        )
+        .is_err());
+
+        assert!(parse(
+            r#"
+            topic: zigbee/+
+            fieldName: temperature
+            tags: {}
+            "#
+        )
+        .is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn topic_matching() -> anyhow::Result<()> {
+        let mapping = parse_topic("foo/+/bar")?;
+        assert!(mapping.matches("foo/baz/bar"));
+        assert!(!mapping.matches("foo/baz"));
+        assert!(!mapping.matches("foo/baz/bar/quux"));
+        assert!(!mapping.matches("foo/baz/quux"));
+
+        let mapping = parse_topic("foo/#")?;
+        assert!(mapping.matches("foo"));
+        assert!(mapping.matches("foo/bar"));
+        assert!(mapping.matches("foo/bar/baz"));
+        assert!(!mapping.matches("bar/foo"));
+
+        let mapping = parse_topic("foo")?;
+        assert_eq!(mapping.qos, QoS::AtLeastOnce);
+
+        Ok(())
+    }
+
+    #[test]
+    fn content_type_filtering() -> anyhow::Result<()> {
+        let mapping = parse_topic("foo")?;
+        assert!(mapping.accepts_content_type(None));
+        assert!(mapping.accepts_content_type(Some("application/json")));
+
+        let mapping = parse_mapping(
+            "{ topic: foo, contentType: application/json, fieldName: value, valueType: text, tags: {} }",
+            &[],
+        )?;
+        assert!(!mapping.accepts_content_type(None));
+        assert!(!mapping.accepts_content_type(Some("text/plain")));
+        assert!(mapping.accepts_content_type(Some("application/json")));
+
+        Ok(())
+    }
+
+    #[test]
+    fn retained_policy() -> anyhow::Result<()> {
+        let parse = |retained: &str| {
+            parse_mapping(
+                &format!("{{ topic: foo, retained: {}, fieldName: value, valueType: text, tags: {{}} }}", retained),
+                &[],
+            )
+        };
+
+        let mapping = parse_topic("foo")?;
+        assert_eq!(mapping.retained, RetainedPolicy::Store);
+        assert!(mapping.claim_retained("site-a", "foo", true));
+        assert!(mapping.claim_retained("site-a", "foo", true));
+
+        let mapping = parse("ignore")?;
+        assert!(mapping.claim_retained("site-a", "foo", false));
+        assert!(!mapping.claim_retained("site-a", "foo", true));
+
+        // Only the first retained message on a topic gets through, even
+        // while it's still being written.
+        let mapping = parse("once")?;
+        assert!(mapping.claim_retained("site-a", "foo", true));
+        assert!(!mapping.claim_retained("site-a", "foo", true));
+        assert!(mapping.claim_retained("site-a", "foo", false));
+        assert!(mapping.claim_retained("site-b", "foo", true));
+        assert!(mapping.claim_retained("site-a", "bar", true));
+
+        // Unless it couldn't be stored.
+        mapping.release_retained("site-a", "foo");
+        assert!(mapping.claim_retained("site-a", "foo", true));
+
+        // Each mapping keeps track of its own, and hands it on to the one
+        // replacing it on a reload.
+        let previous = [Arc::new(parse("once")?), Arc::new(mapping)];
+        let other = parse_mapping("{ topic: bar, retained: once, fieldName: value, valueType: text, tags: {} }", &[])?;
+        let mut mappings = [parse("once")?, parse("once")?, other];
+        carry_over_retained(&mut mappings, &previous);
+        assert!(mappings[0].claim_retained("site-a", "foo", true));
+        assert!(!mappings[1].claim_retained("site-a", "foo", true));
+        assert!(mappings[2].claim_retained("site-a", "bar", true));
+
+        Ok(())
+    }
+
+    #[test]
+    fn qos_parsing() -> anyhow::Result<()> {
+        let parse = |qos: &str| {
+            parse_mapping(
+                &format!("{{ topic: foo, qos: {}, fieldName: value, valueType: text, tags: {{}} }}", qos),
+                &[],
+            )
+        };
+
+        assert_eq!(parse("0")?.qos, QoS::AtMostOnce);
+        assert_eq!(parse("2")?.qos, QoS::ExactlyOnce);
+        assert_eq!(parse("~")?.qos, QoS::AtLeastOnce);
+        assert!(parse("3").is_err());
 
         Ok(())
     }
@@ -0,0 +1,437 @@
+// mqtt2db -- subscries to MQTT topics and writes to a database
+// Copyright (C) 2021-2022 Brian Tarricone <brian@tarricone.org>
+// 
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+// 
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+// 
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use bytes::Bytes;
+use http::{HeaderMap, Request};
+use rumqttc::v5::{
+    mqttbytes::{
+        v5::{
+            Filter as V5Filter, LastWill as V5LastWill, Packet as V5Packet, Publish as V5Publish,
+            SubscribeReasonCode as V5SubscribeReasonCode,
+        },
+        QoS as V5QoS,
+    },
+    AsyncClient as V5AsyncClient, Event as V5Event, EventLoop as V5EventLoop, MqttOptions as V5MqttOptions,
+};
+use rumqttc::{
+    AsyncClient as V4AsyncClient, Event as V4Event, EventLoop as V4EventLoop, LastWill as V4LastWill,
+    MqttOptions as V4MqttOptions,
+    Packet as V4Packet, Publish as V4Publish, QoS, SubscribeFilter as V4SubscribeFilter, SubscribeReasonCode as V4SubscribeReasonCode,
+    TlsConfiguration, Transport,
+};
+use std::future::{ready, Ready};
+use tokio::fs;
+
+use crate::config::{MqttAuth, MqttConfig, ProtocolVersion};
+
+/// A message received from the broker, independent of the protocol
+/// version it arrived over.
+#[derive(Debug)]
+pub struct Message {
+    pub topic: String,
+    pub payload: Bytes,
+    /// Only set for MQTT 5 messages.
+    pub content_type: Option<String>,
+    /// Only set for MQTT 5 messages.
+    pub user_properties: Vec<(String, String)>,
+    /// Whether this is a retained message the broker sent on subscribing,
+    /// rather than a new one.
+    pub retain: bool,
+    /// What's needed to acknowledge the message once it has been handled.
+    qos: QoS,
+    pkid: u16,
+}
+
+impl Message {
+    /// QoS 0 messages are never acknowledged, nor sent again.
+    pub fn needs_ack(&self) -> bool {
+        self.qos != QoS::AtMostOnce
+    }
+
+    /// A plain QoS 0 message, as the broker would deliver it.
+    #[cfg(test)]
+    pub fn new(topic: &str, payload: &str) -> Message {
+        Message {
+            topic: topic.to_string(),
+            payload: Bytes::copy_from_slice(payload.as_bytes()),
+            content_type: None,
+            user_properties: Vec::new(),
+            retain: false,
+            qos: QoS::AtMostOnce,
+            pkid: 0,
+        }
+    }
+}
+
+pub enum MqttEvent {
+    /// The broker accepted our connection; unless it still had our
+    /// session, there are no subscriptions yet.
+    Connected { session_present: bool },
+    Message(Message),
+    Other,
+}
+
+#[derive(Clone)]
+pub enum MqttClient {
+    V4(V4AsyncClient),
+    V5(V5AsyncClient),
+}
+
+pub enum MqttEventLoop {
+    V4(Box<V4EventLoop>),
+    V5(Box<V5EventLoop>),
+}
+
+fn to_v5_qos(qos: QoS) -> V5QoS {
+    match qos {
+        QoS::AtMostOnce => V5QoS::AtMostOnce,
+        QoS::AtLeastOnce => V5QoS::AtLeastOnce,
+        QoS::ExactlyOnce => V5QoS::ExactlyOnce,
+    }
+}
+
+fn from_v5_qos(qos: V5QoS) -> QoS {
+    match qos {
+        V5QoS::AtMostOnce => QoS::AtMostOnce,
+        V5QoS::AtLeastOnce => QoS::AtLeastOnce,
+        V5QoS::ExactlyOnce => QoS::ExactlyOnce,
+    }
+}
+
+impl MqttClient {
+    pub async fn subscribe_many(&self, topics: &[(String, QoS)]) -> anyhow::Result<()> {
+        match self {
+            MqttClient::V4(client) => {
+                let filters = topics
+                    .iter()
+                    .map(|(topic, qos)| V4SubscribeFilter::new(topic.clone(), *qos));
+                client.subscribe_many(filters).await?;
+            }
+            MqttClient::V5(client) => {
+                let filters = topics
+                    .iter()
+                    .map(|(topic, qos)| V5Filter::new(topic.clone(), to_v5_qos(*qos)));
+                client.subscribe_many(filters).await?;
+            }
+        }
+        Ok(())
+    }
+
+    pub async fn publish_retained(&self, topic: &str, payload: &str) -> anyhow::Result<()> {
+        match self {
+            MqttClient::V4(client) => client.publish(topic, QoS::AtLeastOnce, true, payload).await?,
+            MqttClient::V5(client) => client.publish(topic, V5QoS::AtLeastOnce, true, payload.to_string()).await?,
+        }
+        Ok(())
+    }
+
+    /// Acknowledges a message, which the broker will otherwise redeliver
+    /// on the next connection.  Does nothing for QoS 0 messages.
+    pub async fn ack(&self, message: &Message) -> anyhow::Result<()> {
+        // Only the QoS and packet id are looked at.
+        match self {
+            MqttClient::V4(client) => {
+                let mut publish = V4Publish::new(message.topic.as_str(), message.qos, Vec::new());
+                publish.pkid = message.pkid;
+                client.ack(&publish).await?;
+            }
+            MqttClient::V5(client) => {
+                let mut publish = V5Publish::new(message.topic.as_str(), to_v5_qos(message.qos), Vec::new(), None);
+                publish.pkid = message.pkid;
+                client.ack(&publish).await?;
+            }
+        }
+        Ok(())
+    }
+
+    pub async fn unsubscribe(&self, topic: &str) -> anyhow::Result<()> {
+        match self {
+            MqttClient::V4(client) => client.unsubscribe(topic).await?,
+            MqttClient::V5(client) => client.unsubscribe(topic).await?,
+        }
+        Ok(())
+    }
+}
+
+impl MqttEventLoop {
+    /// Returns the next event; an error means the connection has failed, and
+    /// polling again will try to reconnect.
+    pub async fn poll(&mut self) -> anyhow::Result<MqttEvent> {
+        match self {
+            MqttEventLoop::V4(event_loop) => match event_loop.poll().await? {
+                V4Event::Incoming(V4Packet::Publish(publish)) => Ok(MqttEvent::Message(Message {
+                    topic: publish.topic,
+                    payload: publish.payload,
+                    content_type: None,
+                    user_properties: Vec::new(),
+                    retain: publish.retain,
+                    qos: publish.qos,
+                    pkid: publish.pkid,
+                })),
+                V4Event::Incoming(V4Packet::ConnAck(connack)) => Ok(MqttEvent::Connected {
+                    session_present: connack.session_present,
+                }),
+                V4Event::Incoming(V4Packet::SubAck(suback)) => {
+                    for code in suback.return_codes.iter() {
+                        if let V4SubscribeReasonCode::Failure = code {
+                            warn!("Broker rejected subscription (packet {})", suback.pkid);
+                        }
+                    }
+                    Ok(MqttEvent::Other)
+                }
+                _ => Ok(MqttEvent::Other),
+            },
+            MqttEventLoop::V5(event_loop) => match event_loop.poll().await? {
+                V5Event::Incoming(V5Packet::ConnAck(connack)) => Ok(MqttEvent::Connected {
+                    session_present: connack.session_present,
+                }),
+                V5Event::Incoming(V5Packet::Publish(publish)) => {
+                    // Errors are reserved for connection failures, so a bad
+                    // message is just skipped.
+                    let topic = match String::from_utf8(publish.topic.to_vec()) {
+                        Ok(topic) => topic,
+                        Err(err) => {
+                            warn!("Ignoring message with invalid topic name: {}", err);
+                            return Ok(MqttEvent::Other);
+                        }
+                    };
+                    let (content_type, user_properties) = match publish.properties {
+                        Some(properties) => (properties.content_type, properties.user_properties),
+                        None => (None, Vec::new()),
+                    };
+                    Ok(MqttEvent::Message(Message {
+                        topic,
+                        payload: publish.payload,
+                        content_type,
+                        user_properties,
+                        retain: publish.retain,
+                        qos: from_v5_qos(publish.qos),
+                        pkid: publish.pkid,
+                    }))
+                }
+                V5Event::Incoming(V5Packet::SubAck(suback)) => {
+                    for code in suback.return_codes.iter() {
+                        if !matches!(code, V5SubscribeReasonCode::Success(_)) {
+                            warn!("Broker rejected subscription (packet {}): {:?}", suback.pkid, code);
+                        }
+                    }
+                    Ok(MqttEvent::Other)
+                }
+                _ => Ok(MqttEvent::Other),
+            },
+        }
+    }
+}
+
+async fn tls_configuration(config: &MqttConfig, ca_file: &str) -> anyhow::Result<TlsConfiguration> {
+    let ca = fs::read(ca_file).await?;
+    let client_auth = match config.auth.as_ref().and_then(MqttAuth::client_certificate) {
+        Some((cert_file, private_key_file)) => {
+            let cert = fs::read(cert_file).await?;
+            let private_key = fs::read(private_key_file).await?;
+            let key_type = private_key_type(&private_key)
+                .map_err(|err| anyhow!("Private key file '{}': {}", private_key_file, err))?;
+            debug!("Using {} private key from '{}'", key_type, private_key_file);
+            Some((cert, private_key))
+        }
+        None => None,
+    };
+    Ok(TlsConfiguration::Simple {
+        ca,
+        alpn: None,
+        client_auth,
+    })
+}
+
+/// Works out what kind of PEM private key this is, so a key the TLS
+/// library can't use is reported up front rather than as a failure to
+/// connect.
+fn private_key_type(pem: &[u8]) -> anyhow::Result<&'static str> {
+    let pem = String::from_utf8_lossy(pem);
+    for label in pem.lines().filter_map(|line| line.trim().strip_prefix("-----BEGIN ")) {
+        match label.trim_end_matches('-') {
+            "RSA PRIVATE KEY" => return Ok("PKCS#1 RSA"),
+            "EC PRIVATE KEY" => return Ok("SEC1 EC"),
+            "PRIVATE KEY" => return Ok("PKCS#8"),
+            "ENCRYPTED PRIVATE KEY" => Err(anyhow!("encrypted private keys are not supported"))?,
+            _ => (),
+        }
+    }
+    Err(anyhow!("no PEM PKCS#1, PKCS#8 or SEC1 EC private key found"))
+}
+
+/// Returns the broker address to hand to `MqttOptions`, which for
+/// WebSockets is a full URL, along with the transport to use.
+async fn transport(config: &MqttConfig) -> anyhow::Result<(String, Transport)> {
+    let tls = match &config.ca_file {
+        Some(ca_file) => Some(tls_configuration(config, ca_file).await?),
+        None => None,
+    };
+    match (&config.websocket, tls) {
+        (None, None) => Ok((config.host.clone(), Transport::Tcp)),
+        (None, Some(tls)) => Ok((config.host.clone(), Transport::Tls(tls))),
+        (Some(websocket), tls) => {
+            let scheme = if tls.is_some() { "wss" } else { "ws" };
+            let url = format!("{}://{}:{}{}", scheme, config.host, config.port, websocket.path);
+            match tls {
+                Some(tls) => Ok((url, Transport::Wss(tls))),
+                None => Ok((url, Transport::Ws)),
+            }
+        }
+    }
+}
+
+fn add_headers(headers: HeaderMap) -> impl Fn(Request<()>) -> Ready<Request<()>> + Send + Sync + 'static {
+    move |mut request| {
+        request.headers_mut().extend(headers.clone());
+        ready(request)
+    }
+}
+
+/// An MQTT 5 session ends as soon as we disconnect unless we ask for an
+/// expiry interval, so a persistent session with none given never expires.
+fn session_expiry_interval(config: &MqttConfig) -> Option<u32> {
+    match config.session_expiry {
+        Some(expiry) => Some(u32::try_from(expiry.as_secs()).unwrap_or(u32::MAX)),
+        None if !config.clean_session => Some(u32::MAX),
+        None => None,
+    }
+}
+
+pub async fn init_mqtt(config: &MqttConfig) -> anyhow::Result<(MqttClient, MqttEventLoop)> {
+    let (broker_addr, transport) = transport(config).await?;
+    let headers = match &config.websocket {
+        Some(websocket) if !websocket.headers.is_empty() => Some(websocket.header_map()?),
+        _ => None,
+    };
+    let credentials = config.auth.as_ref().and_then(MqttAuth::credentials);
+
+    match config.protocol_version {
+        ProtocolVersion::V311 => {
+            let mut options = V4MqttOptions::new(&config.client_id, broker_addr, config.port);
+            if let Some(keep_alive) = config.keep_alive {
+                options.set_keep_alive(keep_alive);
+            }
+            options.set_clean_session(config.clean_session);
+            if let Some(status) = &config.status {
+                options.set_last_will(V4LastWill::new(&status.topic, status.offline.as_str(), QoS::AtLeastOnce, true));
+            }
+            options.set_manual_acks(true);
+            options.set_transport(transport);
+            if let Some(headers) = headers {
+                options.set_request_modifier(add_headers(headers));
+            }
+            if let Some((username, password)) = credentials {
+                options.set_credentials(username, password);
+            }
+
+            let (client, mut event_loop) = V4AsyncClient::new(options, 100);
+            if let Some(connect_timeout) = config.connect_timeout {
+                let mut network_options = event_loop.network_options();
+                network_options.set_connection_timeout(connect_timeout.as_secs());
+                event_loop.set_network_options(network_options);
+            }
+            Ok((MqttClient::V4(client), MqttEventLoop::V4(Box::new(event_loop))))
+        }
+        ProtocolVersion::V5 => {
+            let mut options = V5MqttOptions::new(&config.client_id, broker_addr, config.port);
+            if let Some(connect_timeout) = config.connect_timeout {
+                options.set_connection_timeout(connect_timeout.as_secs());
+            }
+            if let Some(keep_alive) = config.keep_alive {
+                options.set_keep_alive(keep_alive);
+            }
+            options.set_clean_start(config.clean_session);
+            options.set_session_expiry_interval(session_expiry_interval(config));
+            if let Some(status) = &config.status {
+                options.set_last_will(V5LastWill::new(
+                    &status.topic,
+                    status.offline.as_str(),
+                    V5QoS::AtLeastOnce,
+                    true,
+                    None,
+                ));
+            }
+            options.set_manual_acks(true);
+            options.set_transport(transport);
+            if let Some(headers) = headers {
+                options.set_request_modifier(add_headers(headers));
+            }
+            if let Some((username, password)) = credentials {
+                options.set_credentials(username, password);
+            }
+
+            let (client, event_loop) = V5AsyncClient::new(options, 100);
+            Ok((MqttClient::V5(client), MqttEventLoop::V5(Box::new(event_loop))))
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_util::{mqtt_config, temp_path};
+
+    #[test]
+    fn private_key_detection() {
+        let pem = |label: &str| format!("-----BEGIN {0}-----\nMIIB\n-----END {0}-----\n", label).into_bytes();
+
+        assert_eq!(private_key_type(&pem("RSA PRIVATE KEY")).ok(), Some("PKCS#1 RSA"));
+        assert_eq!(private_key_type(&pem("PRIVATE KEY")).ok(), Some("PKCS#8"));
+        // `openssl ecparam -genkey` puts the curve parameters before the key.
+        let ec = [pem("EC PARAMETERS"), pem("EC PRIVATE KEY")].concat();
+        assert_eq!(private_key_type(&ec).ok(), Some("SEC1 EC"));
+
+        assert!(private_key_type(&pem("ENCRYPTED PRIVATE KEY")).is_err());
+        assert!(private_key_type(&pem("CERTIFICATE")).is_err());
+        assert!(private_key_type(&[0x30, 0x82, 0x01]).is_err());
+    }
+
+    #[test]
+    fn session_expiry() -> anyhow::Result<()> {
+        let expiry = |yaml: &str| -> anyhow::Result<Option<u32>> {
+            Ok(session_expiry_interval(&mqtt_config(&format!("protocolVersion: 5, {}", yaml))?))
+        };
+
+        assert_eq!(expiry("")?, None);
+        assert_eq!(expiry("cleanSession: false")?, Some(u32::MAX));
+        assert_eq!(expiry("cleanSession: false, sessionExpiry: { secs: 3600, nanos: 0 }")?, Some(3600));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn websocket_transport() -> anyhow::Result<()> {
+        let ca_file = temp_path("ca.pem");
+        fs::write(&ca_file, "-----BEGIN CERTIFICATE-----\nMIIB\n-----END CERTIFICATE-----\n").await?;
+        let transport = |yaml: String| async move { transport(&mqtt_config(&yaml)?).await };
+
+        let (url, ws) = transport("websocket: {}".to_string()).await?;
+        assert_eq!(url, "ws://h:1883/mqtt");
+        assert!(matches!(ws, Transport::Ws));
+
+        let (url, wss) = transport(format!("websocket: {{ path: /ws }}, caFile: '{}'", ca_file)).await?;
+        assert_eq!(url, "wss://h:1883/ws");
+        assert!(matches!(wss, Transport::Wss(_)));
+
+        let (host, tcp) = transport("".to_string()).await?;
+        assert_eq!(host, "h");
+        assert!(matches!(tcp, Transport::Tcp));
+
+        fs::remove_file(&ca_file).await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,43 @@
+// mqtt2db -- subscries to MQTT topics and writes to a database
+// Copyright (C) 2021-2022 Brian Tarricone <brian@tarricone.org>
+// 
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+// 
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+// 
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use influxdb::Type;
+
+use crate::config::MqttConfig;
+use crate::database::Point;
+
+/// A path in the temp dir, unique to this process, with whatever an earlier
+/// run left there removed.
+pub fn temp_path(name: &str) -> String {
+    let path = std::env::temp_dir().join(format!("mqtt2db-test-{}-{}", std::process::id(), name));
+    let _ = std::fs::remove_file(&path);
+    path.to_string_lossy().to_string()
+}
+
+/// A point with a single `value` field and a `room: kitchen` tag.
+pub fn mk_point(timestamp: u128, value: f64) -> Point {
+    Point {
+        measurement: None,
+        timestamp,
+        fields: vec![("value".to_string(), Type::Float(value))],
+        tags: vec![("room".to_string(), Type::Text("kitchen".to_string()))],
+    }
+}
+
+/// Parses a broker config made of the required keys plus the given extra ones.
+pub fn mqtt_config(yaml: &str) -> anyhow::Result<MqttConfig> {
+    Ok(serde_yaml::from_str(&format!("{{ host: h, port: 1883, clientId: c, {} }}", yaml))?)
+}
@@ -15,11 +15,11 @@
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
 use influxdb::Type;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use serde_json::Value as JsonValue;
 use std::fmt;
 
-#[derive(Copy, Clone, Debug, Deserialize)]
+#[derive(Copy, Clone, Debug, Deserialize, Serialize)]
 #[serde(rename_all = "kebab-case")]
 pub enum ValueType {
     Boolean,
@@ -55,15 +55,15 @@ impl ToInfluxType for String {
             ValueType::Boolean => Err(anyhow!("Value '{}' is not a valid boolean", self)),
             ValueType::Float => self
                 .parse::<f64>()
-                .map(|v| Type::Float(v))
+                .map(Type::Float)
                 .map_err(|err| err.into()),
             ValueType::SignedInteger => self
                 .parse::<i64>()
-                .map(|v| Type::SignedInteger(v))
+                .map(Type::SignedInteger)
                 .map_err(|err| err.into()),
             ValueType::UnsignedInteger => self
                 .parse::<u64>()
-                .map(|v| Type::UnsignedInteger(v))
+                .map(Type::UnsignedInteger)
                 .map_err(|err| err.into()),
             ValueType::Text => Ok(Type::Text(self.clone())),
         }
@@ -78,15 +78,15 @@ impl ToInfluxType for JsonValue {
             (ValueType::Float, JsonValue::Number(num)) => num
                 .as_f64()
                 .ok_or_else(|| anyhow!("Cannot be expressed as f64: {}", num))
-                .map(|v| Type::Float(v)),
+                .map(Type::Float),
             (ValueType::SignedInteger, JsonValue::Number(num)) => num
                 .as_i64()
                 .ok_or_else(|| anyhow!("Cannot be expressed as i64: {}", num))
-                .map(|v| Type::SignedInteger(v)),
+                .map(Type::SignedInteger),
             (ValueType::UnsignedInteger, JsonValue::Number(num)) => num
                 .as_u64()
                 .ok_or_else(|| anyhow!("Cannot be expressed as u64: {}", num))
-                .map(|v| Type::UnsignedInteger(v)),
+                .map(Type::UnsignedInteger),
             (ValueType::Text, JsonValue::String(s)) => Ok(Type::Text(s.to_string())),
             (ValueType::Text, JsonValue::Bool(b)) => Ok(Type::Text(b.to_string())),
             (ValueType::Text, JsonValue::Number(num)) => Ok(Type::Text(num.to_string())),